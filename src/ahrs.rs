@@ -0,0 +1,456 @@
+use core::fmt;
+use libm::{asinf, atan2f, cosf, sinf, sqrtf};
+
+use crate::driver::l3gd20::AngularRate;
+use crate::driver::lsm::acc::Acceleration;
+use crate::driver::lsm::mag::MagField;
+use crate::ecompass::{angle_difference, wrap_degrees, Attitude, ECompass, DEG_TO_RAD, RAD_TO_DEG};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Algorithm {
+    /// Blend gyroscope integration with the e-compass attitude.
+    /// `alpha` is the weight of the gyroscope, between 0 and 1. Without a
+    /// gyroscope it acts as a low-pass filter on the e-compass attitude.
+    Complementary { alpha: f32 },
+    /// Madgwick's gradient descent filter. `beta` is the gain of the
+    /// accelerometer and magnetometer correction, in rad/s.
+    Madgwick { beta: f32 },
+}
+
+/// Orientation of the car in a north-west-up frame, with the car's axes
+/// pointing forward (x), left (y) and up (z)
+#[derive(Copy, Clone, Debug)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    pub fn identity() -> Self {
+        Self {
+            w: 1.,
+            x: 0.,
+            y: 0.,
+            z: 0.,
+        }
+    }
+
+    /// The quaternion for an attitude relative to magnetic north
+    pub fn from_attitude(attitude: &Attitude) -> Self {
+        // Pitch and heading in `Attitude` are rotations in the opposite
+        // direction of those about the y and z axes
+        let (sr, cr) = half_angle(attitude.roll);
+        let (sp, cp) = half_angle(-attitude.pitch);
+        let (sy, cy) = half_angle(-attitude.heading);
+
+        Self {
+            w: cr * cp * cy + sr * sp * sy,
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+        }
+    }
+
+    /// The attitude with a heading relative to magnetic north
+    pub fn to_attitude(&self) -> Attitude {
+        let Self { w, x, y, z } = *self;
+        let roll = atan2f(2. * (w * x + y * z), 1. - 2. * (x * x + y * y));
+        let pitch = asinf((2. * (w * y - z * x)).clamp(-1., 1.));
+        let yaw = atan2f(2. * (w * z + x * y), 1. - 2. * (y * y + z * z));
+
+        Attitude {
+            roll: roll * RAD_TO_DEG,
+            pitch: -pitch * RAD_TO_DEG,
+            heading: wrap_degrees(-yaw * RAD_TO_DEG),
+        }
+    }
+
+    fn normalized(self) -> Self {
+        let norm = sqrtf(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z);
+        if norm == 0. {
+            return Self::identity();
+        }
+        Self {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+}
+
+fn half_angle(degrees: f32) -> (f32, f32) {
+    let rad = degrees * DEG_TO_RAD / 2.;
+    (sinf(rad), cosf(rad))
+}
+
+/// Normalize a vector, or `None` if it has zero length
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if norm == 0. {
+        None
+    } else {
+        Some([v[0] / norm, v[1] / norm, v[2] / norm])
+    }
+}
+
+/// Attitude and heading reference system, fusing accelerometer, magnetometer
+/// and optionally gyroscope samples at a fixed update rate.
+///
+/// The gyroscope axes are assumed to be aligned with those of the LSM303DLHC.
+pub struct Ahrs {
+    algorithm: Algorithm,
+    /// Time between updates in seconds
+    dt: f32,
+    ecompass: ECompass,
+    q: Quaternion,
+    initialized: bool,
+}
+
+impl Ahrs {
+    /// Create an AHRS that is updated `rate_hz` times per second.
+    /// `declination` is applied to the reported heading, as in `ECompass`.
+    pub fn new(algorithm: Algorithm, rate_hz: u32, declination: f32) -> Self {
+        Self {
+            algorithm,
+            dt: 1. / rate_hz as f32,
+            ecompass: ECompass::new(declination),
+            q: Quaternion::identity(),
+            initialized: false,
+        }
+    }
+
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+    }
+
+    pub fn set_declination(&mut self, declination: f32) {
+        self.ecompass.set_declination(declination);
+    }
+
+    /// Start over from the next sample's e-compass attitude
+    pub fn reset(&mut self) {
+        self.initialized = false;
+    }
+
+    /// Orientation relative to magnetic north
+    pub fn quaternion(&self) -> &Quaternion {
+        &self.q
+    }
+
+    /// Attitude with the heading relative to true north
+    pub fn attitude(&self) -> Attitude {
+        let mut attitude = self.q.to_attitude();
+        attitude.heading = wrap_degrees(attitude.heading + self.ecompass.declination());
+        attitude
+    }
+
+    pub fn heading(&self) -> f32 {
+        self.attitude().heading
+    }
+
+    pub fn update(&mut self, acc: &Acceleration, mag: &MagField, gyro: Option<&AngularRate>) {
+        if !self.initialized {
+            // Start from the e-compass so that the filters don't have to converge
+            self.q = Quaternion::from_attitude(&self.magnetic_attitude(acc, mag));
+            self.initialized = true;
+            return;
+        }
+
+        match self.algorithm {
+            Algorithm::Complementary { alpha } => self.update_complementary(alpha, acc, mag, gyro),
+            Algorithm::Madgwick { beta } => self.update_madgwick(beta, acc, mag, gyro),
+        }
+    }
+
+    /// The e-compass attitude, with the heading relative to magnetic north
+    fn magnetic_attitude(&self, acc: &Acceleration, mag: &MagField) -> Attitude {
+        let mut attitude = self.ecompass.attitude(acc, mag);
+        attitude.heading = wrap_degrees(attitude.heading - self.ecompass.declination());
+        attitude
+    }
+
+    fn update_complementary(
+        &mut self,
+        alpha: f32,
+        acc: &Acceleration,
+        mag: &MagField,
+        gyro: Option<&AngularRate>,
+    ) {
+        let measured = self.magnetic_attitude(acc, mag);
+        let estimate = self.q.to_attitude();
+        let predicted = match gyro {
+            // Small angle approximation of the attitude rates
+            Some(gyro) => Attitude {
+                roll: estimate.roll + gyro.x * self.dt,
+                pitch: estimate.pitch - gyro.y * self.dt,
+                heading: estimate.heading - gyro.z * self.dt,
+            },
+            None => estimate,
+        };
+
+        let blend =
+            |predicted: f32, measured: f32| predicted + (1. - alpha) * (measured - predicted);
+        self.q = Quaternion::from_attitude(&Attitude {
+            roll: blend(predicted.roll, measured.roll),
+            pitch: blend(predicted.pitch, measured.pitch),
+            heading: wrap_degrees(
+                predicted.heading
+                    + (1. - alpha) * angle_difference(predicted.heading, measured.heading),
+            ),
+        });
+    }
+
+    /// Port of the reference implementation of Madgwick's MARG filter
+    fn update_madgwick(
+        &mut self,
+        beta: f32,
+        acc: &Acceleration,
+        mag: &MagField,
+        gyro: Option<&AngularRate>,
+    ) {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.q;
+        let (gx, gy, gz) = match gyro {
+            Some(gyro) => (
+                gyro.x * DEG_TO_RAD,
+                gyro.y * DEG_TO_RAD,
+                gyro.z * DEG_TO_RAD,
+            ),
+            None => (0., 0., 0.),
+        };
+
+        // Rate of change of the quaternion from the gyroscope
+        let mut q_dot0 = 0.5 * (-q1 * gx - q2 * gy - q3 * gz);
+        let mut q_dot1 = 0.5 * (q0 * gx + q2 * gz - q3 * gy);
+        let mut q_dot2 = 0.5 * (q0 * gy - q1 * gz + q3 * gx);
+        let mut q_dot3 = 0.5 * (q0 * gz + q1 * gy - q2 * gx);
+
+        let a = normalize([acc.x, acc.y, acc.z]);
+        let m = normalize([mag.x, mag.y, mag.z]);
+        if let (Some([ax, ay, az]), Some([mx, my, mz])) = (a, m) {
+            let _2q0mx = 2. * q0 * mx;
+            let _2q0my = 2. * q0 * my;
+            let _2q0mz = 2. * q0 * mz;
+            let _2q1mx = 2. * q1 * mx;
+            let _2q0 = 2. * q0;
+            let _2q1 = 2. * q1;
+            let _2q2 = 2. * q2;
+            let _2q3 = 2. * q3;
+            let _2q0q2 = 2. * q0 * q2;
+            let _2q2q3 = 2. * q2 * q3;
+            let q0q0 = q0 * q0;
+            let q0q1 = q0 * q1;
+            let q0q2 = q0 * q2;
+            let q0q3 = q0 * q3;
+            let q1q1 = q1 * q1;
+            let q1q2 = q1 * q2;
+            let q1q3 = q1 * q3;
+            let q2q2 = q2 * q2;
+            let q2q3 = q2 * q3;
+            let q3q3 = q3 * q3;
+
+            // Reference direction of the earth's magnetic field
+            let hx =
+                mx * q0q0 - _2q0my * q3 + _2q0mz * q2 + mx * q1q1 + _2q1 * my * q2 + _2q1 * mz * q3
+                    - mx * q2q2
+                    - mx * q3q3;
+            let hy = _2q0mx * q3 + my * q0q0 - _2q0mz * q1 + _2q1mx * q2 - my * q1q1
+                + my * q2q2
+                + _2q2 * mz * q3
+                - my * q3q3;
+            let _2bx = sqrtf(hx * hx + hy * hy);
+            let _2bz = -_2q0mx * q2 + _2q0my * q1 + mz * q0q0 + _2q1mx * q3 - mz * q1q1
+                + _2q2 * my * q3
+                - mz * q2q2
+                + mz * q3q3;
+            let _4bx = 2. * _2bx;
+            let _4bz = 2. * _2bz;
+
+            // Objective function errors
+            let fa_x = 2. * q1q3 - _2q0q2 - ax;
+            let fa_y = 2. * q0q1 + _2q2q3 - ay;
+            let fa_z = 1. - 2. * q1q1 - 2. * q2q2 - az;
+            let fm_x = _2bx * (0.5 - q2q2 - q3q3) + _2bz * (q1q3 - q0q2) - mx;
+            let fm_y = _2bx * (q1q2 - q0q3) + _2bz * (q0q1 + q2q3) - my;
+            let fm_z = _2bx * (q0q2 + q1q3) + _2bz * (0.5 - q1q1 - q2q2) - mz;
+
+            // Gradient descent step
+            let s0 = -_2q2 * fa_x + _2q1 * fa_y - _2bz * q2 * fm_x
+                + (-_2bx * q3 + _2bz * q1) * fm_y
+                + _2bx * q2 * fm_z;
+            let s1 = _2q3 * fa_x + _2q0 * fa_y - 4. * q1 * fa_z
+                + _2bz * q3 * fm_x
+                + (_2bx * q2 + _2bz * q0) * fm_y
+                + (_2bx * q3 - _4bz * q1) * fm_z;
+            let s2 = -_2q0 * fa_x + _2q3 * fa_y - 4. * q2 * fa_z
+                + (-_4bx * q2 - _2bz * q0) * fm_x
+                + (_2bx * q1 + _2bz * q3) * fm_y
+                + (_2bx * q0 - _4bz * q2) * fm_z;
+            let s3 = _2q1 * fa_x
+                + _2q2 * fa_y
+                + (-_4bx * q3 + _2bz * q1) * fm_x
+                + (-_2bx * q0 + _2bz * q2) * fm_y
+                + _2bx * q1 * fm_z;
+
+            let norm = sqrtf(s0 * s0 + s1 * s1 + s2 * s2 + s3 * s3);
+            if norm > 0. {
+                q_dot0 -= beta * s0 / norm;
+                q_dot1 -= beta * s1 / norm;
+                q_dot2 -= beta * s2 / norm;
+                q_dot3 -= beta * s3 / norm;
+            }
+        }
+
+        self.q = Quaternion {
+            w: q0 + q_dot0 * self.dt,
+            x: q1 + q_dot1 * self.dt,
+            y: q2 + q_dot2 * self.dt,
+            z: q3 + q_dot3 * self.dt,
+        }
+        .normalized();
+    }
+}
+
+/// Write one row of a sensor trace in the CSV format of the traces under
+/// `testdata`: acceleration in mg, field in mG and rate in dps, each x, y, z.
+/// Print a row per update over the serial port to record a trace on the board.
+pub fn write_trace_row(
+    w: &mut impl fmt::Write,
+    acc: &Acceleration,
+    mag: &MagField,
+    gyro: &AngularRate,
+) -> fmt::Result {
+    writeln!(
+        w,
+        "{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.2},{:.2},{:.2}",
+        acc.x, acc.y, acc.z, mag.x, mag.y, mag.z, gyro.x, gyro.y, gyro.z
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These traces are synthetic, generated from a sensor model with noise
+    // and gyroscope bias. They check that the filters converge, not how they
+    // cope with the real sensors. Traces recorded on the board with
+    // `write_trace_row` belong in `testdata/recorded`.
+    const STATIC_TRACE: &str = include_str!("../testdata/synthetic/ahrs_static.csv");
+    const ROTATING_TRACE: &str = include_str!("../testdata/synthetic/ahrs_rotating.csv");
+    const TRACE_RATE_HZ: u32 = 100;
+
+    fn parse_trace(trace: &str) -> Vec<(Acceleration, MagField, AngularRate)> {
+        trace
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let v: Vec<f32> = line.split(',').map(|c| c.parse().unwrap()).collect();
+                (
+                    Acceleration {
+                        x: v[0],
+                        y: v[1],
+                        z: v[2],
+                    },
+                    MagField {
+                        x: v[3],
+                        y: v[4],
+                        z: v[5],
+                    },
+                    AngularRate {
+                        x: v[6],
+                        y: v[7],
+                        z: v[8],
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn run_trace(algorithm: Algorithm, trace: &str) -> Attitude {
+        let mut ahrs = Ahrs::new(algorithm, TRACE_RATE_HZ, 0.);
+        for (acc, mag, gyro) in parse_trace(trace) {
+            ahrs.update(&acc, &mag, Some(&gyro));
+        }
+        ahrs.attitude()
+    }
+
+    fn assert_attitude(attitude: &Attitude, roll: f32, pitch: f32, heading: f32, tolerance: f32) {
+        assert!(
+            (attitude.roll - roll).abs() < tolerance
+                && (attitude.pitch - pitch).abs() < tolerance
+                && angle_difference(attitude.heading, heading).abs() < tolerance,
+            "{:?} is not within {} degrees of roll {}, pitch {}, heading {}",
+            attitude,
+            tolerance,
+            roll,
+            pitch,
+            heading
+        );
+    }
+
+    fn algorithms() -> [Algorithm; 2] {
+        [
+            Algorithm::Complementary { alpha: 0.98 },
+            Algorithm::Madgwick { beta: 0.1 },
+        ]
+    }
+
+    #[test]
+    fn static_trace_settles_on_attitude() {
+        for algorithm in algorithms().iter() {
+            let attitude = run_trace(*algorithm, STATIC_TRACE);
+            assert_attitude(&attitude, 8., -12., 135., 2.);
+        }
+    }
+
+    #[test]
+    fn rotating_trace_follows_heading() {
+        for algorithm in algorithms().iter() {
+            let attitude = run_trace(*algorithm, ROTATING_TRACE);
+            assert_attitude(&attitude, 0., 0., 180., 3.);
+        }
+    }
+
+    #[test]
+    fn trace_row_parses_back() {
+        let trace = STATIC_TRACE.lines().filter(|line| !line.starts_with('#'));
+        let mut written = String::new();
+        for (acc, mag, gyro) in parse_trace(STATIC_TRACE).iter().take(10) {
+            write_trace_row(&mut written, acc, mag, gyro).unwrap();
+        }
+        for (written, expected) in written.lines().zip(trace) {
+            let written: Vec<f32> = written.split(',').map(|c| c.parse().unwrap()).collect();
+            let expected: Vec<f32> = expected.split(',').map(|c| c.parse().unwrap()).collect();
+            assert_eq!(written.len(), 9);
+            for (w, e) in written.iter().zip(expected.iter()) {
+                assert!((w - e).abs() < 0.06, "{} != {}", w, e);
+            }
+        }
+    }
+
+    #[test]
+    fn attitude_round_trip() {
+        for &(roll, pitch, heading) in &[
+            (0., 0., 0.),
+            (10., 0., 90.),
+            (0., 20., 200.),
+            (-15., -25., 300.),
+            (170., 60., 359.),
+        ] {
+            let attitude = Attitude {
+                roll,
+                pitch,
+                heading,
+            };
+            let round_trip = Quaternion::from_attitude(&attitude).to_attitude();
+            assert_attitude(&round_trip, roll, pitch, heading, 0.01);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use core::fmt;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::driver::compass::{Compass, Led, LedError};
+use crate::driver::lsm::acc::{Acceleration, LSM303LDHC_ACC};
+use crate::driver::lsm::calibration::{AccCalibration, Face, MagCalibration, MagCalibrator};
+use crate::driver::lsm::mag::LSM303LDHC_MAG;
+use crate::driver::lsm::Error;
+use crate::driver::motor::Motors;
+use crate::time::{Delay, Duration, Time};
+use crate::{uprint, uprintln};
+
+/// Calibrate the magnetometer by spinning the car in place for `duration`.
+/// As the car only turns about the z axis, this is a yaw-only calibration
+/// that leaves the z axis uncorrected, see `MagCalibrator::finish_xy`.
+/// The resulting calibration is not applied to `mag`, so that the caller can
+/// inspect and store it first.
+pub fn calibrate_spinning<E, TI2C: WriteRead<Error = E> + Write<Error = E>>(
+    mag: &mut LSM303LDHC_MAG<TI2C>,
+    motors: &mut Motors<TI2C>,
+    i2c: &mut TI2C,
+    time: Time,
+    speed: i16,
+    duration: Duration,
+) -> Result<Option<MagCalibration>, Error<E>> {
+    let mut calibrator = MagCalibrator::new();
+    let previous = mag.take_calibration();
+
+    motors.set_speeds(i2c, speed, -speed)?;
+    let delay = Delay::new(duration, time);
+    while delay.poll(time).is_err() {
+        match mag.read_field(i2c) {
+            Ok(field) => calibrator.add_sample(&field),
+            Err(Error::Saturated) => continue,
+            Err(e) => {
+                motors.all_off(i2c).ok();
+                mag.set_calibration(previous);
+                return Err(e);
+            }
+        }
+    }
+    motors.all_off(i2c)?;
+
+    mag.set_calibration(previous);
+    Ok(calibrator.finish_xy())
+}
+
+/// Light the LEDs on the side of the car that should point up
+fn prompt(face: Face, compass: &mut Compass) -> Result<(), LedError> {
+    use Led::*;
+    compass.set_all_low()?;
+    match face {
+        Face::XUp => compass.set_high(North),
+        Face::XDown => compass.set_high(South),
+        Face::YUp => compass.set_high(West),
+        Face::YDown => compass.set_high(East),
+        Face::ZUp => compass.set_all_high(),
+        Face::ZDown => [NorthEast, SouthEast, SouthWest, NorthWest]
+            .iter()
+            .try_for_each(|l| compass.set_high(*l)),
+    }
+}
+
+/// Guide the user through placing the car on each of its six faces,
+/// prompting on the compass LEDs and the serial port, and compute the
+/// accelerometer calibration. The result is not applied to `acc`.
+/// Returns `None` if the readings don't give a usable calibration.
+pub fn calibrate_six_position<E, TI2C: WriteRead<Error = E> + Write<Error = E>>(
+    acc: &mut LSM303LDHC_ACC<TI2C>,
+    i2c: &mut TI2C,
+    compass: &mut Compass,
+    serial: &mut impl fmt::Write,
+    time: Time,
+    samples_per_face: u16,
+) -> Result<Option<AccCalibration>, E> {
+    // Time the car must rest on a face before samples are taken
+    const SETTLE: Duration = Duration::from_secs(1);
+
+    let previous = acc.take_calibration();
+    let scale = acc.scale();
+    let mut faces: [Acceleration; 6] = Default::default();
+
+    for (face, average) in Face::all().iter().zip(faces.iter_mut()) {
+        prompt(*face, compass).ok();
+        uprintln!(serial, "Place the car {}", face.describe());
+
+        // Wait until the car has rested on the face for a while
+        let mut settle = Delay::new(SETTLE, time);
+        loop {
+            let sample = nb::block!(acc.read_sample(i2c));
+            let sample = match sample {
+                Ok(sample) => sample.to_mg(scale),
+                Err(e) => {
+                    acc.set_calibration(previous);
+                    return Err(e);
+                }
+            };
+            if !face.matches(&sample) {
+                settle = Delay::new(SETTLE, time);
+            } else if settle.poll(time).is_ok() {
+                break;
+            }
+        }
+
+        uprintln!(serial, "Measuring, hold still");
+        let mut sum = [0f32; 3];
+        for _ in 0..samples_per_face {
+            match nb::block!(acc.read_sample(i2c)) {
+                Ok(sample) => {
+                    let sample = sample.to_mg(scale);
+                    sum[0] += sample.x;
+                    sum[1] += sample.y;
+                    sum[2] += sample.z;
+                }
+                Err(e) => {
+                    acc.set_calibration(previous);
+                    return Err(e);
+                }
+            }
+        }
+        let n = samples_per_face.max(1) as f32;
+        *average = Acceleration {
+            x: sum[0] / n,
+            y: sum[1] / n,
+            z: sum[2] / n,
+        };
+        uprintln!(
+            serial,
+            "Average: x {} y {} z {} mg",
+            average.x,
+            average.y,
+            average.z
+        );
+    }
+
+    compass.set_all_low().ok();
+    acc.set_calibration(previous);
+
+    let calibration = AccCalibration::from_faces(&faces);
+    if let Some(calibration) = &calibration {
+        uprintln!(
+            serial,
+            "Offset: {:?} mg, scale: {:?}",
+            calibration.offset,
+            calibration.scale
+        );
+    } else {
+        uprintln!(
+            serial,
+            "Calibration failed, check that each face was measured"
+        );
+    }
+    Ok(calibration)
+}
@@ -1,8 +1,9 @@
 use core::ops::{Deref, DerefMut};
 use stm32f3xx_hal::gpio::{gpioe::*, Output, PushPull};
+use stm32f3xx_hal::hal::blocking::delay::DelayMs;
 use stm32f3xx_hal::hal::digital::v2::OutputPin;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Led {
     NorthWest,
     North,
@@ -27,8 +28,39 @@ impl Led {
             Self::NorthWest,
         ]
     }
+
+    /// The LED closest to a bearing in degrees, clockwise from the North LED
+    pub fn from_bearing(bearing: f32) -> Led {
+        let index = (bearing_to_index(bearing) + 0.5) as usize % 8;
+        Self::all()[index]
+    }
+
+    /// The LED or pair of neighbouring LEDs closest to a bearing.
+    /// Returns a single LED if the bearing is within 11.25 degrees of it,
+    /// otherwise the two LEDs the bearing lies between.
+    pub fn pair_from_bearing(bearing: f32) -> (Led, Option<Led>) {
+        let index = bearing_to_index(bearing);
+        let lower = index as usize % 8;
+        let fraction = index - lower as f32;
+        if fraction < 0.25 {
+            (Self::all()[lower], None)
+        } else if fraction > 0.75 {
+            (Self::all()[(lower + 1) % 8], None)
+        } else {
+            (Self::all()[lower], Some(Self::all()[(lower + 1) % 8]))
+        }
+    }
 }
 
+/// Map a bearing to a position in `[0, 8)` on the LED ring
+fn bearing_to_index(bearing: f32) -> f32 {
+    crate::ecompass::wrap_degrees(bearing) / 45.
+}
+
+/// Driving one of the LED pins failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LedError;
+
 pub struct Compass {
     north_west: PE8<Output<PushPull>>,
     north: PE9<Output<PushPull>>,
@@ -79,15 +111,15 @@ impl Compass {
         }
     }
 
-    pub fn set_all_high(&mut self) -> Result<(), ()> {
+    pub fn set_all_high(&mut self) -> Result<(), LedError> {
         Led::all().iter().try_for_each(|l| self.set_high(*l))
     }
 
-    pub fn set_all_low(&mut self) -> Result<(), ()> {
+    pub fn set_all_low(&mut self) -> Result<(), LedError> {
         Led::all().iter().try_for_each(|l| self.set_low(*l))
     }
 
-    pub fn set_high(&mut self, led: Led) -> Result<(), ()> {
+    pub fn set_high(&mut self, led: Led) -> Result<(), LedError> {
         match led {
             Led::NorthWest => self.north_west.set_high(),
             Led::North => self.north.set_high(),
@@ -98,9 +130,10 @@ impl Compass {
             Led::SouthWest => self.south_west.set_high(),
             Led::West => self.west.set_high(),
         }
+        .map_err(|()| LedError)
     }
 
-    pub fn set_low(&mut self, led: Led) -> Result<(), ()> {
+    pub fn set_low(&mut self, led: Led) -> Result<(), LedError> {
         match led {
             Led::NorthWest => self.north_west.set_low(),
             Led::North => self.north.set_low(),
@@ -111,23 +144,42 @@ impl Compass {
             Led::SouthWest => self.south_west.set_low(),
             Led::West => self.west.set_low(),
         }
+        .map_err(|()| LedError)
     }
 
-    pub fn set_direction(&mut self, led: Led) -> Result<(), ()> {
+    pub fn set_direction(&mut self, led: Led) -> Result<(), LedError> {
         self.set_all_low()?;
         self.set_high(led)
     }
 
-    pub fn blink(&mut self, led: Led, times: u16) -> Result<(), ()> {
+    /// Light the LED pointing at `bearing` degrees clockwise from the North LED.
+    /// If `interpolate` is set, bearings in between two LEDs light both.
+    pub fn point_to(&mut self, bearing: f32, interpolate: bool) -> Result<(), LedError> {
+        match Led::pair_from_bearing(bearing) {
+            (first, Some(second)) if interpolate => {
+                self.set_all_low()?;
+                self.set_high(first)?;
+                self.set_high(second)
+            }
+            _ => self.set_direction(Led::from_bearing(bearing)),
+        }
+    }
+
+    pub fn blink(
+        &mut self,
+        led: Led,
+        times: u16,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), LedError> {
         match led {
-            Led::NorthWest => blink_led(&mut self.north_west, times),
-            Led::North => blink_led(&mut self.north, times),
-            Led::NorthEast => blink_led(&mut self.north_east, times),
-            Led::East => blink_led(&mut self.east, times),
-            Led::SouthEast => blink_led(&mut self.south_east, times),
-            Led::South => blink_led(&mut self.south, times),
-            Led::SouthWest => blink_led(&mut self.south_west, times),
-            Led::West => blink_led(&mut self.west, times),
+            Led::NorthWest => blink_led(&mut self.north_west, times, delay),
+            Led::North => blink_led(&mut self.north, times, delay),
+            Led::NorthEast => blink_led(&mut self.north_east, times, delay),
+            Led::East => blink_led(&mut self.east, times, delay),
+            Led::SouthEast => blink_led(&mut self.south_east, times, delay),
+            Led::South => blink_led(&mut self.south, times, delay),
+            Led::SouthWest => blink_led(&mut self.south_west, times, delay),
+            Led::West => blink_led(&mut self.west, times, delay),
         }
     }
 }
@@ -141,11 +193,11 @@ impl Dial {
         Self { compass }
     }
 
-    pub fn reset(&mut self) -> Result<(), ()> {
+    pub fn reset(&mut self) -> Result<(), LedError> {
         self.compass.set_all_low()
     }
 
-    pub fn set_magnitude(&mut self, mag: usize) -> Result<(), ()> {
+    pub fn set_magnitude(&mut self, mag: usize) -> Result<(), LedError> {
         debug_assert!(mag <= 8);
         self.reset()?;
         Led::all()
@@ -155,12 +207,74 @@ impl Dial {
     }
 }
 
-fn blink_led(led: &mut impl OutputPin<Error = ()>, times: u16) -> Result<(), ()> {
+/// Keeps the LED that points at magnetic north lit as the car turns.
+/// Assumes the North LED points to the front of the car.
+pub struct NorthPointer {
+    compass: Compass,
+    interpolate: bool,
+    lit: Option<(Led, Option<Led>)>,
+}
+
+impl NorthPointer {
+    pub fn new(compass: Compass, interpolate: bool) -> Self {
+        Self {
+            compass,
+            interpolate,
+            lit: None,
+        }
+    }
+
+    /// Update the LEDs for the car's current heading in degrees.
+    /// The LEDs are only written if the pointer moves.
+    pub fn update(&mut self, heading: f32) -> Result<(), LedError> {
+        // North lies at the opposite of the heading, relative to the car
+        let bearing = -heading;
+        let leds = match Led::pair_from_bearing(bearing) {
+            pair @ (_, Some(_)) if self.interpolate => pair,
+            _ => (Led::from_bearing(bearing), None),
+        };
+        if self.lit == Some(leds) {
+            return Ok(());
+        }
+
+        self.compass.point_to(bearing, self.interpolate)?;
+        self.lit = Some(leds);
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), LedError> {
+        self.lit = None;
+        self.compass.set_all_low()
+    }
+
+    pub fn release(self) -> Compass {
+        self.compass
+    }
+}
+
+impl Deref for NorthPointer {
+    type Target = Compass;
+    fn deref(&self) -> &Self::Target {
+        &self.compass
+    }
+}
+
+impl DerefMut for NorthPointer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.compass
+    }
+}
+
+fn blink_led(
+    led: &mut impl OutputPin<Error = ()>,
+    times: u16,
+    delay: &mut impl DelayMs<u16>,
+) -> Result<(), LedError> {
     for _ in 0..times {
-        led.set_high()?;
-        crate::busy_wait(100);
-        led.set_low()?;
-        crate::busy_wait(100);
+        led.set_high().map_err(|()| LedError)?;
+        delay.delay_ms(100);
+        led.set_low().map_err(|()| LedError)?;
+        delay.delay_ms(100);
     }
     Ok(())
 }
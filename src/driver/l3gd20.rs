@@ -0,0 +1,347 @@
+use core::convert::TryInto;
+use core::marker::PhantomData;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+
+/// Expected contents of WHO_AM_I
+pub const L3GD20_ID: u8 = 0xD4;
+
+/// Set in the address byte to read instead of write
+const READ: u8 = 0b1000_0000;
+/// Set in the address byte to auto-increment the address
+const MULTIPLE: u8 = 0b0100_0000;
+
+#[derive(Debug)]
+pub enum Error<E, PE> {
+    Spi(E),
+    /// Driving the chip select pin failed
+    Pin(PE),
+    /// The device responded with an unexpected WHO_AM_I value
+    WrongDevice(u8),
+}
+
+pub struct L3GD20<TSPI, TCS> {
+    cs: TCS,
+    range: Range,
+    phantom: PhantomData<TSPI>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GyroData {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// Angular rate in degrees per second
+#[derive(Clone, Debug)]
+pub struct AngularRate {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Output data rate (CTRL_REG1 DR bits)
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum DataRate {
+    Rate95Hz = 0b00 << 6,
+    Rate190Hz = 0b01 << 6,
+    Rate380Hz = 0b10 << 6,
+    Rate760Hz = 0b11 << 6,
+}
+
+/// Low-pass bandwidth selection (CTRL_REG1 BW bits).
+/// The actual cutoff frequency depends on the output data rate (table 21).
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Bandwidth {
+    Lowest = 0b00 << 4,
+    Low = 0b01 << 4,
+    High = 0b10 << 4,
+    Highest = 0b11 << 4,
+}
+
+/// Full-scale selection (CTRL_REG4 FS bits)
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Range {
+    Dps250 = 0b00 << 4,
+    Dps500 = 0b01 << 4,
+    Dps2000 = 0b10 << 4,
+}
+
+impl Range {
+    /// Sensitivity in millidegrees per second per digit (table 4)
+    pub fn mdps_per_digit(&self) -> f32 {
+        match self {
+            Range::Dps250 => 8.75,
+            Range::Dps500 => 17.5,
+            Range::Dps2000 => 70.,
+        }
+    }
+}
+
+/// FIFO mode (FIFO_CTRL_REG FM bits)
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum FifoMode {
+    Bypass = 0b000 << 5,
+    /// Stop collecting once the FIFO is full
+    Fifo = 0b001 << 5,
+    /// Discard the oldest samples once the FIFO is full
+    Stream = 0b010 << 5,
+    StreamToFifo = 0b011 << 5,
+    BypassToStream = 0b100 << 5,
+}
+
+/// Contents of STATUS_REG
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// A new sample is available on all axes
+    pub available: bool,
+    /// A sample was overwritten before it was read
+    pub overrun: bool,
+}
+
+impl From<u8> for Status {
+    fn from(bits: u8) -> Self {
+        Self {
+            available: bits & 1 << 3 != 0,
+            overrun: bits & 1 << 7 != 0,
+        }
+    }
+}
+
+/// Contents of FIFO_SRC_REG
+#[derive(Clone, Debug)]
+pub struct FifoStatus {
+    pub watermark: bool,
+    pub overrun: bool,
+    pub empty: bool,
+    /// Number of unread samples in the FIFO
+    pub level: u8,
+}
+
+impl From<u8> for FifoStatus {
+    fn from(bits: u8) -> Self {
+        let empty = bits & 1 << 5 != 0;
+        let overrun = bits & 1 << 6 != 0;
+        Self {
+            watermark: bits & 1 << 7 != 0,
+            overrun,
+            empty,
+            // The level field saturates at 31, while the FIFO holds 32 samples
+            level: if overrun { 32 } else { bits & 0b1_1111 },
+        }
+    }
+}
+
+impl<E, PE, TSPI, TCS> L3GD20<TSPI, TCS>
+where
+    TSPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    TCS: OutputPin<Error = PE>,
+{
+    pub fn init(spi: &mut TSPI, cs: TCS, data_rate: DataRate) -> Result<Self, Error<E, PE>> {
+        let mut gyro = Self {
+            cs,
+            range: Range::Dps250,
+            phantom: PhantomData,
+        };
+        gyro.cs.set_high().map_err(Error::Pin)?;
+
+        let id = gyro.read_reg(spi, register::WHO_AM_I)?;
+        if id != L3GD20_ID {
+            return Err(Error::WrongDevice(id));
+        }
+
+        gyro.set_data_rate(spi, data_rate, Bandwidth::Lowest)?;
+        gyro.set_range(spi, Range::Dps250)?;
+        Ok(gyro)
+    }
+
+    /// Release the chip select pin
+    pub fn release(self) -> TCS {
+        self.cs
+    }
+
+    pub fn read_reg(&mut self, spi: &mut TSPI, reg: u8) -> Result<u8, Error<E, PE>> {
+        let mut buf = [reg | READ, 0];
+        self.transfer(spi, &mut buf)?;
+
+        Ok(buf[1])
+    }
+
+    pub fn write_reg(&mut self, spi: &mut TSPI, reg: u8, value: u8) -> Result<(), Error<E, PE>> {
+        self.cs.set_low().map_err(Error::Pin)?;
+        let res = spi.write(&[reg, value]);
+        self.cs.set_high().map_err(Error::Pin)?;
+        res.map_err(Error::Spi)
+    }
+
+    /// Run a single transfer with chip select asserted
+    fn transfer(&mut self, spi: &mut TSPI, buf: &mut [u8]) -> Result<(), Error<E, PE>> {
+        self.cs.set_low().map_err(Error::Pin)?;
+        let res = spi.transfer(buf).map(|_| ());
+        self.cs.set_high().map_err(Error::Pin)?;
+        res.map_err(Error::Spi)
+    }
+
+    /// Power up the sensor with all axes enabled, at the given rate and bandwidth
+    pub fn set_data_rate(
+        &mut self,
+        spi: &mut TSPI,
+        data_rate: DataRate,
+        bandwidth: Bandwidth,
+    ) -> Result<(), Error<E, PE>> {
+        // PD, Zen, Yen and Xen bits
+        let power_up = 0b1111;
+        self.write_reg(
+            spi,
+            register::CTRL_REG1,
+            data_rate as u8 | bandwidth as u8 | power_up,
+        )
+    }
+
+    pub fn power_down(&mut self, spi: &mut TSPI) -> Result<(), Error<E, PE>> {
+        self.write_reg(spi, register::CTRL_REG1, 0x00)
+    }
+
+    pub fn set_range(&mut self, spi: &mut TSPI, range: Range) -> Result<(), Error<E, PE>> {
+        // Enable block data update, so that the high and low bytes
+        // of a sample are always read from the same sample
+        let bdu = 1 << 7;
+        self.write_reg(spi, register::CTRL_REG4, bdu | range as u8)?;
+        self.range = range;
+        Ok(())
+    }
+
+    /// Enable the FIFO in the given mode. `watermark` sets the level
+    /// at which the watermark flag is raised.
+    pub fn set_fifo_mode(
+        &mut self,
+        spi: &mut TSPI,
+        mode: FifoMode,
+        watermark: u8,
+    ) -> Result<(), Error<E, PE>> {
+        let fifo_en = 1 << 6;
+        let ctrl_reg5 = self.read_reg(spi, register::CTRL_REG5)?;
+        let ctrl_reg5 = match mode {
+            FifoMode::Bypass => ctrl_reg5 & !fifo_en,
+            _ => ctrl_reg5 | fifo_en,
+        };
+        self.write_reg(spi, register::CTRL_REG5, ctrl_reg5)?;
+        self.write_reg(
+            spi,
+            register::FIFO_CTRL_REG,
+            mode as u8 | watermark.min(0b1_1111),
+        )
+    }
+
+    pub fn read_fifo_status(&mut self, spi: &mut TSPI) -> Result<FifoStatus, Error<E, PE>> {
+        self.read_reg(spi, register::FIFO_SRC_REG)
+            .map(FifoStatus::from)
+    }
+
+    /// Read up to `samples.len()` samples from the FIFO,
+    /// returning the number of samples read
+    pub fn read_fifo(
+        &mut self,
+        spi: &mut TSPI,
+        samples: &mut [GyroData],
+    ) -> Result<usize, Error<E, PE>> {
+        let level = self.read_fifo_status(spi)?.level as usize;
+        let count = level.min(samples.len());
+        for sample in samples.iter_mut().take(count) {
+            *sample = self.read_output(spi)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Connect or disconnect the data ready signal to the INT2/DRDY pin
+    pub fn route_data_ready(&mut self, spi: &mut TSPI, enable: bool) -> Result<(), Error<E, PE>> {
+        let i2_drdy = 1 << 3;
+        let ctrl_reg3 = self.read_reg(spi, register::CTRL_REG3)?;
+        let ctrl_reg3 = if enable {
+            ctrl_reg3 | i2_drdy
+        } else {
+            ctrl_reg3 & !i2_drdy
+        };
+        self.write_reg(spi, register::CTRL_REG3, ctrl_reg3)
+    }
+
+    pub fn read_status(&mut self, spi: &mut TSPI) -> Result<Status, Error<E, PE>> {
+        self.read_reg(spi, register::STATUS_REG).map(Status::from)
+    }
+
+    /// Read a new sample, or `WouldBlock` if no new data is available
+    /// since the previous read
+    pub fn read_sample(&mut self, spi: &mut TSPI) -> nb::Result<GyroData, Error<E, PE>> {
+        if !self.read_status(spi)?.available {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        Ok(self.read_output(spi)?)
+    }
+
+    /// Read a new sample converted to degrees per second using the selected range
+    pub fn read_rate(&mut self, spi: &mut TSPI) -> nb::Result<AngularRate, Error<E, PE>> {
+        let GyroData { x, y, z } = self.read_sample(spi)?;
+        let scale = self.range.mdps_per_digit() / 1000.;
+
+        Ok(AngularRate {
+            x: x as f32 * scale,
+            y: y as f32 * scale,
+            z: z as f32 * scale,
+        })
+    }
+
+    /// Read the output registers, regardless of whether they contain new data
+    pub fn read_output(&mut self, spi: &mut TSPI) -> Result<GyroData, Error<E, PE>> {
+        let mut buf = [0u8; 7];
+        buf[0] = register::OUT_X_L | READ | MULTIPLE;
+        self.transfer(spi, &mut buf)?;
+
+        let x = i16::from_le_bytes(buf[1..=2].try_into().unwrap());
+        let y = i16::from_le_bytes(buf[3..=4].try_into().unwrap());
+        let z = i16::from_le_bytes(buf[5..=6].try_into().unwrap());
+
+        Ok(GyroData { x, y, z })
+    }
+}
+
+#[allow(dead_code)]
+pub mod register {
+    pub const WHO_AM_I: u8 = 0x0F;
+
+    pub const CTRL_REG1: u8 = 0x20;
+    pub const CTRL_REG2: u8 = 0x21;
+    pub const CTRL_REG3: u8 = 0x22;
+    pub const CTRL_REG4: u8 = 0x23;
+    pub const CTRL_REG5: u8 = 0x24;
+
+    pub const REFERENCE: u8 = 0x25;
+    pub const OUT_TEMP: u8 = 0x26;
+    pub const STATUS_REG: u8 = 0x27;
+
+    pub const OUT_X_L: u8 = 0x28;
+    pub const OUT_X_H: u8 = 0x29;
+    pub const OUT_Y_L: u8 = 0x2A;
+    pub const OUT_Y_H: u8 = 0x2B;
+    pub const OUT_Z_L: u8 = 0x2C;
+    pub const OUT_Z_H: u8 = 0x2D;
+
+    pub const FIFO_CTRL_REG: u8 = 0x2E;
+    pub const FIFO_SRC_REG: u8 = 0x2F;
+
+    pub const INT1_CFG: u8 = 0x30;
+    pub const INT1_SRC: u8 = 0x31;
+    pub const INT1_TSH_XH: u8 = 0x32;
+    pub const INT1_TSH_XL: u8 = 0x33;
+    pub const INT1_TSH_YH: u8 = 0x34;
+    pub const INT1_TSH_YL: u8 = 0x35;
+    pub const INT1_TSH_ZH: u8 = 0x36;
+    pub const INT1_TSH_ZL: u8 = 0x37;
+    pub const INT1_DURATION: u8 = 0x38;
+}
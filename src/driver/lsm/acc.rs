@@ -2,10 +2,17 @@ use core::convert::TryInto;
 use core::marker::PhantomData;
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 
+use super::calibration::AccCalibration;
+use super::Error;
+
 pub const LSM_ACC_I2C_ADDR: u8 = 0b0011001;
 
 #[allow(non_camel_case_types)]
 pub struct LSM303LDHC_ACC<TI2C> {
+    data_rate: DataRate,
+    scale: Scale,
+    overruns: u32,
+    calibration: Option<AccCalibration>,
     phantom: PhantomData<TI2C>,
 }
 
@@ -16,7 +23,28 @@ pub struct AccData {
     pub z: i16,
 }
 
-#[derive(Clone, Debug)]
+impl AccData {
+    /// Convert to mg for the given full-scale selection
+    pub fn to_mg(&self, scale: Scale) -> Acceleration {
+        // Samples are 12 bits, left-justified in 16
+        let mg = scale.mg_per_digit();
+        Acceleration {
+            x: (self.x >> 4) as f32 * mg,
+            y: (self.y >> 4) as f32 * mg,
+            z: (self.z >> 4) as f32 * mg,
+        }
+    }
+}
+
+/// Acceleration in mg
+#[derive(Clone, Debug, Default)]
+pub struct Acceleration {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum DataRate {
@@ -30,28 +58,610 @@ pub enum DataRate {
     Rate400Hz = 0b0111 << 4,
 }
 
-impl<TI2C: WriteRead + Write> LSM303LDHC_ACC<TI2C> {
-    pub fn init(i2c: &mut TI2C, data_rate: DataRate) -> Result<Self, <TI2C as Write>::Error> {
+impl DataRate {
+    pub fn hz(&self) -> u32 {
+        match self {
+            DataRate::PowerDown => 0,
+            DataRate::Rate1hz => 1,
+            DataRate::Rate10Hz => 10,
+            DataRate::Rate25Hz => 25,
+            DataRate::Rate50Hz => 50,
+            DataRate::Rate100Hz => 100,
+            DataRate::Rate200Hz => 200,
+            DataRate::Rate400Hz => 400,
+        }
+    }
+}
+
+/// Full-scale selection (CTRL_REG4_A FS bits)
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum Scale {
+    G2 = 0b00 << 4,
+    G4 = 0b01 << 4,
+    G8 = 0b10 << 4,
+    G16 = 0b11 << 4,
+}
+
+impl Scale {
+    /// Sensitivity of the 12-bit output in mg per digit (table 3)
+    pub fn mg_per_digit(&self) -> f32 {
+        match self {
+            Scale::G2 => 1.,
+            Scale::G4 => 2.,
+            Scale::G8 => 4.,
+            Scale::G16 => 12.,
+        }
+    }
+
+    /// Weight of one LSB of the 7-bit threshold registers, in mg (table 85)
+    pub fn threshold_lsb_mg(&self) -> u16 {
+        match self {
+            Scale::G2 => 16,
+            Scale::G4 => 32,
+            Scale::G8 => 62,
+            Scale::G16 => 186,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AxisFlags {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+    /// Set for a complete set of x, y and z
+    pub xyz: bool,
+}
+
+impl AxisFlags {
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            x: bits & 1 << 0 != 0,
+            y: bits & 1 << 1 != 0,
+            z: bits & 1 << 2 != 0,
+            xyz: bits & 1 << 3 != 0,
+        }
+    }
+}
+
+/// Contents of STATUS_REG_A
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// New data is available
+    pub available: AxisFlags,
+    /// New data has overwritten data that was not read yet
+    pub overrun: AxisFlags,
+}
+
+impl From<u8> for Status {
+    fn from(bits: u8) -> Self {
+        Self {
+            available: AxisFlags::from_bits(bits),
+            overrun: AxisFlags::from_bits(bits >> 4),
+        }
+    }
+}
+
+/// High-pass filter mode (CTRL_REG2_A HPM bits)
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum FilterMode {
+    /// Normal mode, the filter is reset by reading REFERENCE_A
+    NormalWithReset = 0b00 << 6,
+    /// Output is filtered relative to the value in REFERENCE_A
+    Reference = 0b01 << 6,
+    Normal = 0b10 << 6,
+    /// The filter is reset on each interrupt event
+    AutoResetOnInterrupt = 0b11 << 6,
+}
+
+/// High-pass cutoff selection (CTRL_REG2_A HPCF bits).
+/// The actual cutoff frequency scales with the output data rate.
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Cutoff {
+    Highest = 0b00 << 4,
+    High = 0b01 << 4,
+    Low = 0b10 << 4,
+    Lowest = 0b11 << 4,
+}
+
+#[derive(Clone, Debug)]
+pub struct FilterConfig {
+    pub mode: FilterMode,
+    pub cutoff: Cutoff,
+    /// Send filtered data to the output registers and FIFO
+    pub output: bool,
+    /// Filter the data used for click detection
+    pub click: bool,
+    pub interrupt1: bool,
+    pub interrupt2: bool,
+}
+
+impl FilterConfig {
+    /// Remove gravity from the output data, leaving only dynamic acceleration
+    pub fn remove_gravity(cutoff: Cutoff) -> Self {
+        Self {
+            mode: FilterMode::Normal,
+            cutoff,
+            output: true,
+            click: false,
+            interrupt1: false,
+            interrupt2: false,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        self.mode as u8
+            | self.cutoff as u8
+            | (self.output as u8) << 3
+            | (self.click as u8) << 2
+            | (self.interrupt2 as u8) << 1
+            | (self.interrupt1 as u8)
+    }
+}
+
+/// One of the two inertial interrupt generators
+#[derive(Copy, Clone, Debug)]
+pub enum Generator {
+    Int1,
+    Int2,
+}
+
+/// The physical interrupt pins on the LSM303DLHC
+#[derive(Copy, Clone, Debug)]
+pub enum Pin {
+    Int1,
+    Int2,
+}
+
+/// How the enabled axis events are combined into an interrupt
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Combination {
+    Or = 0b00 << 6,
+    And = 0b10 << 6,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AxisEvents {
+    pub x_low: bool,
+    pub x_high: bool,
+    pub y_low: bool,
+    pub y_high: bool,
+    pub z_low: bool,
+    pub z_high: bool,
+}
+
+impl AxisEvents {
+    /// All high events, used to detect the car being moved or picked up
+    pub fn any_high() -> Self {
+        Self {
+            x_high: true,
+            y_high: true,
+            z_high: true,
+            ..Self::default()
+        }
+    }
+
+    /// All low events, which combined with `Combination::And` detect free-fall
+    pub fn all_low() -> Self {
+        Self {
+            x_low: true,
+            y_low: true,
+            z_low: true,
+            ..Self::default()
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        (self.x_low as u8)
+            | (self.x_high as u8) << 1
+            | (self.y_low as u8) << 2
+            | (self.y_high as u8) << 3
+            | (self.z_low as u8) << 4
+            | (self.z_high as u8) << 5
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            x_low: bits & 1 << 0 != 0,
+            x_high: bits & 1 << 1 != 0,
+            y_low: bits & 1 << 2 != 0,
+            y_high: bits & 1 << 3 != 0,
+            z_low: bits & 1 << 4 != 0,
+            z_high: bits & 1 << 5 != 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterruptConfig {
+    pub combination: Combination,
+    pub events: AxisEvents,
+    pub threshold_mg: u16,
+    pub duration_ms: u16,
+    /// Keep the interrupt asserted until the source register is read
+    pub latch: bool,
+}
+
+impl InterruptConfig {
+    /// Fires when any axis exceeds the threshold, e.g. when the car is picked up
+    pub fn motion(threshold_mg: u16, duration_ms: u16) -> Self {
+        Self {
+            combination: Combination::Or,
+            events: AxisEvents::any_high(),
+            threshold_mg,
+            duration_ms,
+            latch: true,
+        }
+    }
+
+    /// Fires when all axes are below the threshold, i.e. the car is falling
+    pub fn free_fall(threshold_mg: u16, duration_ms: u16) -> Self {
+        Self {
+            combination: Combination::And,
+            events: AxisEvents::all_low(),
+            threshold_mg,
+            duration_ms,
+            latch: true,
+        }
+    }
+}
+
+/// Contents of INTx_SRC_A
+#[derive(Clone, Debug)]
+pub struct InterruptSource {
+    pub active: bool,
+    pub events: AxisEvents,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ClickAxes {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+impl ClickAxes {
+    pub fn all() -> Self {
+        Self {
+            x: true,
+            y: true,
+            z: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClickConfig {
+    pub axes: ClickAxes,
+    pub single: bool,
+    pub double: bool,
+    pub threshold_mg: u16,
+    /// Maximum time the acceleration may stay above the threshold for a click
+    pub time_limit_ms: u16,
+    /// Time after the first click during which clicks are ignored
+    pub latency_ms: u16,
+    /// Time after the latency in which a second click must start for a double click
+    pub window_ms: u16,
+}
+
+impl ClickConfig {
+    /// Detect taps on the roof of the car, i.e. along the z axis
+    pub fn roof_tap(threshold_mg: u16) -> Self {
+        Self {
+            axes: ClickAxes {
+                z: true,
+                ..ClickAxes::default()
+            },
+            single: true,
+            double: true,
+            threshold_mg,
+            time_limit_ms: 50,
+            latency_ms: 100,
+            window_ms: 300,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        let axes = [self.axes.x, self.axes.y, self.axes.z];
+        axes.iter()
+            .enumerate()
+            .filter(|(_, enabled)| **enabled)
+            .fold(0, |bits, (i, _)| {
+                bits | (self.single as u8) << (2 * i) | (self.double as u8) << (2 * i + 1)
+            })
+    }
+}
+
+/// Contents of CLICK_SRC_A
+#[derive(Clone, Debug)]
+pub struct ClickSource {
+    pub active: bool,
+    pub single: bool,
+    pub double: bool,
+    /// The click was detected with a negative acceleration
+    pub negative: bool,
+    pub axes: ClickAxes,
+}
+
+impl From<u8> for ClickSource {
+    fn from(bits: u8) -> Self {
+        Self {
+            active: bits & 1 << 6 != 0,
+            double: bits & 1 << 5 != 0,
+            single: bits & 1 << 4 != 0,
+            negative: bits & 1 << 3 != 0,
+            axes: ClickAxes {
+                z: bits & 1 << 2 != 0,
+                y: bits & 1 << 1 != 0,
+                x: bits & 1 << 0 != 0,
+            },
+        }
+    }
+}
+
+impl<E, TI2C: WriteRead<Error = E> + Write<Error = E>> LSM303LDHC_ACC<TI2C> {
+    pub fn init(i2c: &mut TI2C, data_rate: DataRate) -> Result<Self, Error<E>> {
+        // The accelerometer has no identification register. Instead, check
+        // that the device responds and that CTRL_REG1_A reads back as written.
+        let mut buf = [0u8];
+        i2c.write_read(LSM_ACC_I2C_ADDR, &[register::CTRL_REG1_A], &mut buf)
+            .map_err(Error::NotFound)?;
+
         // set data rate (table 20)
         // Optionally set low-power mode, z, y , x axes enamble
+        let ctrl_reg1 = data_rate as u8 | 0b0111;
+        i2c.write(LSM_ACC_I2C_ADDR, &[register::CTRL_REG1_A, ctrl_reg1])?;
 
-        i2c.write(
-            LSM_ACC_I2C_ADDR,
-            &[register::CTRL_REG1_A, data_rate as u8 | 0b0111],
-        )?;
+        i2c.write_read(LSM_ACC_I2C_ADDR, &[register::CTRL_REG1_A], &mut buf)?;
+        if buf[0] != ctrl_reg1 {
+            return Err(Error::WrongDevice);
+        }
 
-        Ok(Self {
+        let mut acc = Self {
+            data_rate,
+            scale: Scale::G2,
+            overruns: 0,
+            calibration: None,
             phantom: PhantomData,
+        };
+        // The registers survive an MCU reset, so a previous run
+        // may have left a different scale selected
+        acc.set_scale(i2c, Scale::G2)?;
+        Ok(acc)
+    }
+
+    pub fn read_reg(&mut self, i2c: &mut TI2C, reg: u8) -> Result<u8, E> {
+        let mut buf = [0u8];
+        i2c.write_read(LSM_ACC_I2C_ADDR, &[reg], &mut buf)?;
+
+        Ok(buf[0])
+    }
+
+    fn write_reg(&mut self, i2c: &mut TI2C, reg: u8, value: u8) -> Result<(), E> {
+        i2c.write(LSM_ACC_I2C_ADDR, &[reg, value])
+    }
+
+    /// Read a register, clear the bits in `mask` and set those in `value`
+    fn modify_reg(&mut self, i2c: &mut TI2C, reg: u8, mask: u8, value: u8) -> Result<(), E> {
+        let current = self.read_reg(i2c, reg)?;
+        self.write_reg(i2c, reg, (current & !mask) | (value & mask))
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, i2c: &mut TI2C, scale: Scale) -> Result<(), E> {
+        self.modify_reg(i2c, register::CTRL_REG4_A, 0b0011_0000, scale as u8)?;
+        self.scale = scale;
+        Ok(())
+    }
+
+    /// Convert a duration in ms to a number of ODR cycles, saturated to `max`
+    fn duration_to_reg(&self, duration_ms: u16, max: u8) -> u8 {
+        let cycles = duration_ms as u32 * self.data_rate.hz() / 1000;
+        cycles.min(max as u32) as u8
+    }
+
+    /// Convert a threshold in mg to threshold register LSBs, saturated to 7 bits
+    fn threshold_to_reg(&self, threshold_mg: u16) -> u8 {
+        (threshold_mg / self.scale.threshold_lsb_mg()).min(0x7F) as u8
+    }
+
+    pub fn configure_filter(&mut self, i2c: &mut TI2C, config: &FilterConfig) -> Result<(), E> {
+        self.write_reg(i2c, register::CTRL_REG2_A, config.bits())
+    }
+
+    pub fn disable_filter(&mut self, i2c: &mut TI2C) -> Result<(), E> {
+        self.write_reg(i2c, register::CTRL_REG2_A, 0x00)
+    }
+
+    /// Set the reference used in `FilterMode::Reference`
+    pub fn set_reference(&mut self, i2c: &mut TI2C, reference: u8) -> Result<(), E> {
+        self.write_reg(i2c, register::REFERENCE_A, reference)
+    }
+
+    /// Reset the filter to the current acceleration.
+    /// Only has effect in `FilterMode::NormalWithReset`, where reading
+    /// REFERENCE_A instantly settles the filter.
+    pub fn reset_filter(&mut self, i2c: &mut TI2C) -> Result<u8, E> {
+        self.read_reg(i2c, register::REFERENCE_A)
+    }
+
+    /// Configure one of the inertial interrupt generators.
+    /// Use `route_interrupt` to have it drive one of the interrupt pins.
+    pub fn configure_interrupt(
+        &mut self,
+        i2c: &mut TI2C,
+        generator: Generator,
+        config: &InterruptConfig,
+    ) -> Result<(), E> {
+        use register::*;
+        let (cfg, ths, duration, lir) = match generator {
+            Generator::Int1 => (INT1_CFG_A, INT1_THS_A, INT1_DURATION_A, 1 << 3),
+            Generator::Int2 => (INT2_CFG_A, INT2_THS_A, INT2_DURATION_A, 1 << 1),
+        };
+
+        // Disable the generator while its threshold and duration are changed
+        self.write_reg(i2c, cfg, 0x00)?;
+        self.write_reg(i2c, ths, self.threshold_to_reg(config.threshold_mg))?;
+        self.write_reg(
+            i2c,
+            duration,
+            self.duration_to_reg(config.duration_ms, 0x7F),
+        )?;
+        self.modify_reg(i2c, CTRL_REG5_A, lir, if config.latch { lir } else { 0 })?;
+        self.write_reg(i2c, cfg, config.combination as u8 | config.events.bits())
+    }
+
+    pub fn disable_interrupt(&mut self, i2c: &mut TI2C, generator: Generator) -> Result<(), E> {
+        let cfg = match generator {
+            Generator::Int1 => register::INT1_CFG_A,
+            Generator::Int2 => register::INT2_CFG_A,
+        };
+        self.write_reg(i2c, cfg, 0x00)
+    }
+
+    /// Connect or disconnect an interrupt generator to one of the interrupt pins
+    pub fn route_interrupt(
+        &mut self,
+        i2c: &mut TI2C,
+        generator: Generator,
+        pin: Pin,
+        enable: bool,
+    ) -> Result<(), E> {
+        use register::{CTRL_REG3_A, CTRL_REG6_A};
+        let (reg, bit) = match (generator, pin) {
+            (Generator::Int1, Pin::Int1) => (CTRL_REG3_A, 1 << 6),
+            (Generator::Int2, Pin::Int1) => (CTRL_REG3_A, 1 << 5),
+            (Generator::Int1, Pin::Int2) => (CTRL_REG6_A, 1 << 6),
+            (Generator::Int2, Pin::Int2) => (CTRL_REG6_A, 1 << 5),
+        };
+        self.modify_reg(i2c, reg, bit, if enable { bit } else { 0 })
+    }
+
+    /// Read which event caused an interrupt. Clears a latched interrupt.
+    pub fn read_interrupt_source(
+        &mut self,
+        i2c: &mut TI2C,
+        generator: Generator,
+    ) -> Result<InterruptSource, E> {
+        let src = match generator {
+            Generator::Int1 => register::INT1_SRC_A,
+            Generator::Int2 => register::INT2_SRC_A,
+        };
+        let bits = self.read_reg(i2c, src)?;
+
+        Ok(InterruptSource {
+            active: bits & 1 << 6 != 0,
+            events: AxisEvents::from_bits(bits),
         })
     }
 
-    pub fn read_sample(&mut self, i2c: &mut TI2C) -> Result<AccData, <TI2C as WriteRead>::Error> {
+    /// Configure single and/or double click detection
+    pub fn configure_click(&mut self, i2c: &mut TI2C, config: &ClickConfig) -> Result<(), E> {
+        use register::*;
+
+        self.write_reg(i2c, CLICK_CFG_A, 0x00)?;
+        self.write_reg(i2c, CLICK_THS_A, self.threshold_to_reg(config.threshold_mg))?;
+        self.write_reg(
+            i2c,
+            TIME_LIMIT_A,
+            self.duration_to_reg(config.time_limit_ms, 0x7F),
+        )?;
+        self.write_reg(
+            i2c,
+            TIME_LATENCY_A,
+            self.duration_to_reg(config.latency_ms, 0xFF),
+        )?;
+        self.write_reg(
+            i2c,
+            TIME_WINDOW_A,
+            self.duration_to_reg(config.window_ms, 0xFF),
+        )?;
+        self.write_reg(i2c, CLICK_CFG_A, config.bits())
+    }
+
+    pub fn disable_click(&mut self, i2c: &mut TI2C) -> Result<(), E> {
+        self.write_reg(i2c, register::CLICK_CFG_A, 0x00)
+    }
+
+    /// Connect or disconnect the click interrupt to one of the interrupt pins
+    pub fn route_click(&mut self, i2c: &mut TI2C, pin: Pin, enable: bool) -> Result<(), E> {
+        let reg = match pin {
+            Pin::Int1 => register::CTRL_REG3_A,
+            Pin::Int2 => register::CTRL_REG6_A,
+        };
+        let bit = 1 << 7;
+        self.modify_reg(i2c, reg, bit, if enable { bit } else { 0 })
+    }
+
+    pub fn read_click_source(&mut self, i2c: &mut TI2C) -> Result<ClickSource, E> {
+        self.read_reg(i2c, register::CLICK_SRC_A)
+            .map(ClickSource::from)
+    }
+
+    /// Set the calibration that `read_acceleration` applies to every reading
+    pub fn set_calibration(&mut self, calibration: Option<AccCalibration>) {
+        self.calibration = calibration;
+    }
+
+    pub fn calibration(&self) -> Option<&AccCalibration> {
+        self.calibration.as_ref()
+    }
+
+    pub fn take_calibration(&mut self) -> Option<AccCalibration> {
+        self.calibration.take()
+    }
+
+    /// Read a new sample in mg, applying the calibration if one is set
+    pub fn read_acceleration(&mut self, i2c: &mut TI2C) -> nb::Result<Acceleration, E> {
+        let acc = self.read_sample(i2c)?.to_mg(self.scale);
+
+        Ok(match &self.calibration {
+            Some(calibration) => calibration.apply(&acc),
+            None => acc,
+        })
+    }
+
+    pub fn read_status(&mut self, i2c: &mut TI2C) -> Result<Status, E> {
+        self.read_reg(i2c, register::STATUS_REG_A).map(Status::from)
+    }
+
+    /// The number of reads by `read_sample` that found the overrun flag set.
+    /// Each counts at least one overwritten sample, but the sensor does not
+    /// report how many were lost in between.
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    /// Read a new sample, or `WouldBlock` if no new data is available
+    /// since the previous read
+    pub fn read_sample(&mut self, i2c: &mut TI2C) -> nb::Result<AccData, E> {
+        let status = self.read_status(i2c)?;
+        if !status.available.xyz {
+            return Err(nb::Error::WouldBlock);
+        }
+        if status.overrun.xyz {
+            self.overruns = self.overruns.wrapping_add(1);
+        }
+
+        Ok(self.read_output(i2c)?)
+    }
+
+    /// Read the output registers, regardless of whether they contain new data
+    pub fn read_output(&mut self, i2c: &mut TI2C) -> Result<AccData, E> {
         use register::OUT_X_L_A;
 
         let mut buf = [0u8; 6];
 
-        // We bitwise set the last bit to 1 to enable auto-increment
-        i2c.write_read(LSM_ACC_I2C_ADDR, &[OUT_X_L_A | 0b0000001], &mut buf)?;
+        // We bitwise set the most significant bit to 1 to enable auto-increment
+        i2c.write_read(LSM_ACC_I2C_ADDR, &[OUT_X_L_A | 0b1000_0000], &mut buf)?;
 
         let x = i16::from_le_bytes(buf[0..=1].try_into().unwrap());
         let y = i16::from_le_bytes(buf[2..=3].try_into().unwrap());
@@ -99,5 +709,5 @@ pub mod register {
 
     pub const TIME_LIMIT_A: u8 = 0x3B;
     pub const TIME_LATENCY_A: u8 = 0x3C;
-    pub const TIME_WINDOW_A: u8 = 0x3;
+    pub const TIME_WINDOW_A: u8 = 0x3D;
 }
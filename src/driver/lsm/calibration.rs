@@ -0,0 +1,496 @@
+use super::acc::Acceleration;
+use super::mag::MagField;
+use libm::sqrtf;
+
+/// Hard- and soft-iron correction for magnetometer readings.
+/// A corrected reading is `soft_iron * (raw - hard_iron)`, in milligauss.
+#[derive(Clone, Debug)]
+pub struct MagCalibration {
+    pub hard_iron: [f32; 3],
+    pub soft_iron: [[f32; 3]; 3],
+}
+
+impl MagCalibration {
+    pub const SERIALIZED_LEN: usize = 12 * 4;
+
+    pub fn identity() -> Self {
+        Self {
+            hard_iron: [0.; 3],
+            soft_iron: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        }
+    }
+
+    pub fn apply(&self, field: &MagField) -> MagField {
+        let v = [
+            field.x - self.hard_iron[0],
+            field.y - self.hard_iron[1],
+            field.z - self.hard_iron[2],
+        ];
+        let m = &self.soft_iron;
+        MagField {
+            x: m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            y: m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            z: m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+        }
+    }
+
+    /// Coefficients as little-endian f32s: the hard-iron offsets,
+    /// followed by the soft-iron matrix in row-major order
+    pub fn to_bytes(&self) -> [u8; Self::SERIALIZED_LEN] {
+        let mut bytes = [0u8; Self::SERIALIZED_LEN];
+        let coefficients = self
+            .hard_iron
+            .iter()
+            .chain(self.soft_iron.iter().flat_map(|row| row.iter()));
+        bytes
+            .chunks_mut(4)
+            .zip(coefficients)
+            .for_each(|(chunk, c)| chunk.copy_from_slice(&c.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SERIALIZED_LEN]) -> Self {
+        let mut c = [0f32; 12];
+        c.iter_mut().zip(bytes.chunks(4)).for_each(|(c, chunk)| {
+            *c = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+        });
+
+        Self {
+            hard_iron: [c[0], c[1], c[2]],
+            soft_iron: [[c[3], c[4], c[5]], [c[6], c[7], c[8]], [c[9], c[10], c[11]]],
+        }
+    }
+}
+
+/// Collects magnetometer samples while the car is rotated through as many
+/// orientations as possible, and computes a `MagCalibration`.
+///
+/// The hard-iron offsets are the centres of the extremes seen on each axis.
+/// The soft-iron matrix, including the cross-axis terms, is the inverse
+/// square root of the covariance of the samples around those offsets, which
+/// turns the ellipsoid into a sphere without rotating it. This assumes the
+/// samples are spread evenly over the ellipsoid, as they are when the car
+/// spins at a steady rate. Uneven coverage, such as lingering in one
+/// orientation, skews the matrix towards the over-sampled directions.
+#[derive(Clone, Debug)]
+pub struct MagCalibrator {
+    min: [f32; 3],
+    max: [f32; 3],
+    /// Sums of the samples and of their pairwise products, for the covariance.
+    /// In f64, as the sums of squares exceed the precision of f32.
+    sum: [f64; 3],
+    sum_products: [[f64; 3]; 3],
+    samples: u32,
+}
+
+impl MagCalibrator {
+    /// Minimum number of samples before a calibration is computed
+    pub const MIN_SAMPLES: u32 = 50;
+    /// Minimum field range in mG an axis must have seen
+    pub const MIN_RANGE: f32 = 100.;
+
+    pub fn new() -> Self {
+        Self {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+            sum: [0.; 3],
+            sum_products: [[0.; 3]; 3],
+            samples: 0,
+        }
+    }
+
+    pub fn add_sample(&mut self, field: &MagField) {
+        let v = [field.x, field.y, field.z];
+        for ((min, max), v) in self.min.iter_mut().zip(self.max.iter_mut()).zip(v.iter()) {
+            *min = min.min(*v);
+            *max = max.max(*v);
+        }
+        for i in 0..3 {
+            self.sum[i] += v[i] as f64;
+            for j in 0..3 {
+                self.sum_products[i][j] += v[i] as f64 * v[j] as f64;
+            }
+        }
+        self.samples += 1;
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Compute the calibration, or `None` if not enough samples were
+    /// collected or an axis was not rotated through a sufficient range.
+    /// Falls back to `finish_xy` if only the z axis was not rotated through
+    /// a sufficient range, as happens when spinning the car on a flat surface.
+    pub fn finish(&self) -> Option<MagCalibration> {
+        self.offset(0)?;
+        self.offset(1)?;
+        if self.offset(2).is_none() {
+            return self.finish_xy();
+        }
+        self.fit(3)
+    }
+
+    /// Compute a yaw-only calibration from the x and y axes. Rotating the car
+    /// flat on the ground only turns it about the z axis, so the z field hardly
+    /// changes and neither its offset nor its scale can be determined: the
+    /// z hard-iron offset is left at 0 and the z soft-iron row and column at
+    /// identity. The z reading therefore keeps its hard-iron error, which
+    /// biases the tilt-compensated heading when the car is not level.
+    pub fn finish_xy(&self) -> Option<MagCalibration> {
+        self.offset(0)?;
+        self.offset(1)?;
+        self.fit(2)
+    }
+
+    /// Centre of the extremes of an axis, or `None` if not enough samples were
+    /// collected or the axis was not rotated through a sufficient range
+    fn offset(&self, axis: usize) -> Option<f32> {
+        let range = self.max[axis] - self.min[axis];
+        if self.samples < Self::MIN_SAMPLES || range < Self::MIN_RANGE {
+            return None;
+        }
+
+        Some((self.max[axis] + self.min[axis]) / 2.)
+    }
+
+    /// Fit the first `axes` axes, leaving the others uncorrected
+    fn fit(&self, axes: usize) -> Option<MagCalibration> {
+        let mut calibration = MagCalibration::identity();
+        for i in 0..axes {
+            calibration.hard_iron[i] = self.offset(i)?;
+        }
+
+        // Covariance around the offsets, rather than around the mean,
+        // which is off centre when the samples don't cover the ellipsoid evenly
+        let n = self.samples as f64;
+        let c = calibration.hard_iron;
+        let mut cov = [[0f32; 3]; 3];
+        for i in 0..axes {
+            for j in 0..axes {
+                let mean_ij = self.sum_products[i][j] / n;
+                let mean_i = self.sum[i] / n;
+                let mean_j = self.sum[j] / n;
+                cov[i][j] = (mean_ij - c[i] as f64 * mean_j - mean_i * c[j] as f64
+                    + c[i] as f64 * c[j] as f64) as f32;
+            }
+        }
+
+        // For samples spread evenly over an ellipsoid, the variance along a
+        // principal axis is its radius squared over the number of dimensions
+        let (values, vectors) = symmetric_eigen(cov, axes);
+        let mut radii = [0f32; 3];
+        for i in 0..axes {
+            if values[i] <= 0. {
+                return None;
+            }
+            radii[i] = sqrtf(values[i] * axes as f32);
+        }
+        // Scale to the average radius, to keep readings in milligauss
+        let avg_radius = radii.iter().sum::<f32>() / axes as f32;
+
+        for i in 0..axes {
+            for j in 0..axes {
+                calibration.soft_iron[i][j] = (0..axes)
+                    .map(|k| vectors[i][k] * vectors[j][k] * avg_radius / radii[k])
+                    .sum();
+            }
+        }
+        Some(calibration)
+    }
+}
+
+/// Eigenvalues and eigenvectors of the upper left `n` by `n` block of a
+/// symmetric matrix, by cyclic Jacobi rotations. Eigenvector `k` is column `k`.
+#[allow(clippy::needless_range_loop)]
+fn symmetric_eigen(mut a: [[f32; 3]; 3], n: usize) -> ([f32; 3], [[f32; 3]; 3]) {
+    let mut v = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+    for _ in 0..16 {
+        let mut off_diagonal = 0.;
+        for p in 0..n {
+            for q in p + 1..n {
+                off_diagonal += a[p][q] * a[p][q];
+            }
+        }
+        if off_diagonal < 1e-12 {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q] == 0. {
+                    continue;
+                }
+                // Rotate by the angle that zeroes a[p][q]
+                let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+                let t = theta.signum() / (theta.abs() + sqrtf(theta * theta + 1.));
+                let cos = 1. / sqrtf(t * t + 1.);
+                let sin = t * cos;
+                for k in 0..n {
+                    let (akp, akq) = (a[k][p], a[k][q]);
+                    a[k][p] = cos * akp - sin * akq;
+                    a[k][q] = sin * akp + cos * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p][k], a[q][k]);
+                    a[p][k] = cos * apk - sin * aqk;
+                    a[q][k] = sin * apk + cos * aqk;
+                }
+                for row in v.iter_mut().take(n) {
+                    let (vp, vq) = (row[p], row[q]);
+                    row[p] = cos * vp - sin * vq;
+                    row[q] = sin * vp + cos * vq;
+                }
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+impl Default for MagCalibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-axis offset and scale correction for accelerometer readings.
+/// A corrected reading is `(raw - offset) * scale`, in mg.
+#[derive(Clone, Debug)]
+pub struct AccCalibration {
+    pub offset: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl AccCalibration {
+    pub const SERIALIZED_LEN: usize = 6 * 4;
+
+    pub fn identity() -> Self {
+        Self {
+            offset: [0.; 3],
+            scale: [1.; 3],
+        }
+    }
+
+    pub fn apply(&self, acc: &Acceleration) -> Acceleration {
+        Acceleration {
+            x: (acc.x - self.offset[0]) * self.scale[0],
+            y: (acc.y - self.offset[1]) * self.scale[1],
+            z: (acc.z - self.offset[2]) * self.scale[2],
+        }
+    }
+
+    /// Minimum difference in mg between the up and down readings of an axis,
+    /// half the 2 g expected
+    pub const MIN_RANGE: f32 = 1000.;
+
+    /// Compute the calibration from averaged readings in mg with each axis
+    /// pointing up and down in turn, indexed by `Face`.
+    /// Returns `None` if an axis did not see at least `MIN_RANGE` between
+    /// pointing up and down, for instance when a face was skipped.
+    pub fn from_faces(faces: &[Acceleration; 6]) -> Option<Self> {
+        let axis = |acc: &Acceleration, i: usize| [acc.x, acc.y, acc.z][i];
+        let mut calibration = Self::identity();
+        for i in 0..3 {
+            let up = axis(&faces[2 * i], i);
+            let down = axis(&faces[2 * i + 1], i);
+            if up - down < Self::MIN_RANGE {
+                return None;
+            }
+            calibration.offset[i] = (up + down) / 2.;
+            calibration.scale[i] = 1000. / ((up - down) / 2.);
+        }
+        Some(calibration)
+    }
+
+    /// Coefficients as little-endian f32s: the offsets, followed by the scales
+    pub fn to_bytes(&self) -> [u8; Self::SERIALIZED_LEN] {
+        let mut bytes = [0u8; Self::SERIALIZED_LEN];
+        let coefficients = self.offset.iter().chain(self.scale.iter());
+        bytes
+            .chunks_mut(4)
+            .zip(coefficients)
+            .for_each(|(chunk, c)| chunk.copy_from_slice(&c.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SERIALIZED_LEN]) -> Self {
+        let mut c = [0f32; 6];
+        c.iter_mut().zip(bytes.chunks(4)).for_each(|(c, chunk)| {
+            *c = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+        });
+
+        Self {
+            offset: [c[0], c[1], c[2]],
+            scale: [c[3], c[4], c[5]],
+        }
+    }
+}
+
+/// The six orientations of the six-position calibration,
+/// named after the axis that points up
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Face {
+    /// Nose up
+    XUp,
+    /// Nose down
+    XDown,
+    /// Left side up
+    YUp,
+    /// Right side up
+    YDown,
+    /// Level on its wheels
+    ZUp,
+    /// Upside down
+    ZDown,
+}
+
+impl Face {
+    pub fn all() -> [Face; 6] {
+        use Face::*;
+        [XUp, XDown, YUp, YDown, ZUp, ZDown]
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Face::XUp => "nose up",
+            Face::XDown => "nose down",
+            Face::YUp => "left side up",
+            Face::YDown => "right side up",
+            Face::ZUp => "level on its wheels",
+            Face::ZDown => "upside down",
+        }
+    }
+
+    /// Whether a reading in mg shows the car resting on this face
+    pub fn matches(&self, acc: &Acceleration) -> bool {
+        // Within about 25 degrees of the face
+        let threshold = 900.;
+        match self {
+            Face::XUp => acc.x > threshold,
+            Face::XDown => acc.x < -threshold,
+            Face::YUp => acc.y > threshold,
+            Face::YDown => acc.y < -threshold,
+            Face::ZUp => acc.z > threshold,
+            Face::ZDown => acc.z < -threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_PI_6;
+    use libm::{cosf, sinf};
+
+    /// Distort unit vectors by `soft_iron * v + hard_iron`
+    fn distort(units: &[[f32; 3]], soft_iron: [[f32; 3]; 3], hard_iron: [f32; 3]) -> Vec<MagField> {
+        units
+            .iter()
+            .map(|u| {
+                let m = &soft_iron;
+                MagField {
+                    x: m[0][0] * u[0] + m[0][1] * u[1] + m[0][2] * u[2] + hard_iron[0],
+                    y: m[1][0] * u[0] + m[1][1] * u[1] + m[1][2] * u[2] + hard_iron[1],
+                    z: m[2][0] * u[0] + m[2][1] * u[1] + m[2][2] * u[2] + hard_iron[2],
+                }
+            })
+            .collect()
+    }
+
+    fn calibrator(samples: &[MagField]) -> MagCalibrator {
+        let mut calibrator = MagCalibrator::new();
+        samples.iter().for_each(|s| calibrator.add_sample(s));
+        calibrator
+    }
+
+    /// Smallest and largest length of the corrected samples
+    fn radius_range(calibration: &MagCalibration, samples: &[MagField], axes: usize) -> (f32, f32) {
+        samples
+            .iter()
+            .map(|s| {
+                let c = calibration.apply(s);
+                let v = [c.x, c.y, c.z];
+                libm::sqrtf(v.iter().take(axes).map(|v| v * v).sum())
+            })
+            .fold((f32::MAX, f32::MIN), |(min, max), r| {
+                (min.min(r), max.max(r))
+            })
+    }
+
+    /// An ellipse in the xy plane with its axes at 30 degrees to the sensor axes
+    fn flat_spin() -> Vec<MagField> {
+        let (c, s) = (cosf(FRAC_PI_6), sinf(FRAC_PI_6));
+        let (a, b) = (300., 200.);
+        let soft_iron = [
+            [a * c * c + b * s * s, (a - b) * c * s, 0.],
+            [(a - b) * c * s, a * s * s + b * c * c, 0.],
+            [0., 0., 1.],
+        ];
+        let units: Vec<_> = (0..360)
+            .map(|i| {
+                let angle = i as f32 * core::f32::consts::PI / 180.;
+                [cosf(angle), sinf(angle), 0.]
+            })
+            .collect();
+        distort(&units, soft_iron, [150., -80., 400.])
+    }
+
+    #[test]
+    fn flat_spin_is_circular_after_correction() {
+        let samples = flat_spin();
+        let calibration = calibrator(&samples).finish_xy().unwrap();
+
+        assert!((calibration.hard_iron[0] - 150.).abs() < 1.);
+        assert!((calibration.hard_iron[1] + 80.).abs() < 1.);
+        assert_eq!(calibration.hard_iron[2], 0.);
+        assert!(calibration.soft_iron[0][1].abs() > 0.1);
+        assert_eq!(calibration.soft_iron[2], [0., 0., 1.]);
+
+        let (min, max) = radius_range(&calibration, &samples, 2);
+        assert!(max - min < 5., "radius between {} and {}", min, max);
+        assert!((min - 250.).abs() < 5.);
+    }
+
+    #[test]
+    fn finish_falls_back_to_xy_for_a_flat_spin() {
+        let samples = flat_spin();
+        let calibration = calibrator(&samples).finish().unwrap();
+        assert_eq!(calibration.hard_iron[2], 0.);
+        assert_eq!(calibration.soft_iron[2], [0., 0., 1.]);
+    }
+
+    #[test]
+    fn ellipsoid_is_spherical_after_correction() {
+        // Points spread evenly over the unit sphere along a Fibonacci spiral
+        let golden_angle = core::f32::consts::PI * (3. - libm::sqrtf(5.));
+        let n = 2000;
+        let units: Vec<_> = (0..n)
+            .map(|i| {
+                let z = 1. - 2. * (i as f32 + 0.5) / n as f32;
+                let r = libm::sqrtf(1. - z * z);
+                let angle = golden_angle * i as f32;
+                [r * cosf(angle), r * sinf(angle), z]
+            })
+            .collect();
+        let soft_iron = [[320., 40., -20.], [40., 260., 30.], [-20., 30., 200.]];
+        let samples = distort(&units, soft_iron, [-60., 120., 35.]);
+        let calibration = calibrator(&samples).finish().unwrap();
+
+        let expected = [-60., 120., 35.];
+        for (offset, expected) in calibration.hard_iron.iter().zip(expected.iter()) {
+            assert!((offset - expected).abs() < 2., "offset {}", offset);
+        }
+        let (min, max) = radius_range(&calibration, &samples, 3);
+        assert!(max - min < 0.02 * max, "radius between {} and {}", min, max);
+    }
+
+    #[test]
+    fn rejects_too_few_samples_or_too_little_rotation() {
+        let samples = flat_spin();
+        assert!(calibrator(&samples[..10]).finish().is_none());
+        // Enough samples, but from a 10 degree wiggle
+        let wiggle: Vec<_> = samples[..10].iter().cycle().take(100).cloned().collect();
+        assert!(calibrator(&wiggle).finish().is_none());
+    }
+}
@@ -1,11 +1,17 @@
 use core::convert::TryInto;
 use core::marker::PhantomData;
+use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 
+use super::calibration::MagCalibration;
+use super::Error;
+
 pub const LSM_MAG_I2C_ADDR: u8 = 0b001_1110;
 
 #[allow(non_camel_case_types)]
 pub struct LSM303LDHC_MAG<TI2C> {
+    gain: Gain,
+    calibration: Option<MagCalibration>,
     phantom: PhantomData<TI2C>,
 }
 
@@ -23,6 +29,65 @@ pub enum DataRate {
     Rate220Hz = 0b111 << 2,
 }
 
+/// Gain selection (CRB_REG_M GN bits), named after the sensor input range
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum Gain {
+    Gauss1_3 = 0b001 << 5,
+    Gauss1_9 = 0b010 << 5,
+    Gauss2_5 = 0b011 << 5,
+    Gauss4_0 = 0b100 << 5,
+    Gauss4_7 = 0b101 << 5,
+    Gauss5_6 = 0b110 << 5,
+    Gauss8_1 = 0b111 << 5,
+}
+
+impl Gain {
+    /// LSB per gauss for the x and y axes, and for the z axis (table 75)
+    pub fn lsb_per_gauss(&self) -> (u16, u16) {
+        match self {
+            Gain::Gauss1_3 => (1100, 980),
+            Gain::Gauss1_9 => (855, 760),
+            Gain::Gauss2_5 => (670, 600),
+            Gain::Gauss4_0 => (450, 400),
+            Gain::Gauss4_7 => (400, 355),
+            Gain::Gauss5_6 => (330, 295),
+            Gain::Gauss8_1 => (230, 205),
+        }
+    }
+}
+
+/// Operating mode (MR_REG_M MD bits)
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    Continuous = 0b00,
+    /// Perform a single conversion, after which the sensor goes to sleep
+    Single = 0b01,
+    Sleep = 0b11,
+}
+
+/// Contents of SR_REG_M
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// The output registers are locked while a sample is being read
+    pub lock: bool,
+    pub data_ready: bool,
+}
+
+impl From<u8> for Status {
+    fn from(bits: u8) -> Self {
+        Self {
+            lock: bits & 1 << 1 != 0,
+            data_ready: bits & 1 << 0 != 0,
+        }
+    }
+}
+
+/// Output value of an axis that exceeded the selected range
+pub const OVERFLOW: i16 = -4096;
+
 #[derive(Clone, Debug)]
 pub struct MagData {
     pub x: i16,
@@ -30,29 +95,149 @@ pub struct MagData {
     pub z: i16,
 }
 
+/// Magnetic field strength in milligauss
+#[derive(Clone, Debug)]
+pub struct MagField {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl MagField {
+    /// Convert to microtesla, where 1 µT equals 10 mG
+    pub fn to_microtesla(&self) -> MagField {
+        MagField {
+            x: self.x / 10.,
+            y: self.y / 10.,
+            z: self.z / 10.,
+        }
+    }
+}
+
+/// Temperature as a signed 12-bit value with a resolution of 8 LSB/°C.
+/// The sensor has no factory calibrated zero point, so converting to
+/// degrees requires an offset determined against a reference thermometer.
 #[derive(Clone, Debug)]
 pub struct TempData(pub i16);
 
-impl<TI2C: WriteRead + Write> LSM303LDHC_MAG<TI2C> {
-    pub fn init(i2c: &mut TI2C, data_rate: DataRate) -> Result<Self, <TI2C as Write>::Error> {
-        i2c.write(LSM_MAG_I2C_ADDR, &[register::MR_REG_M, 0x00])?;
+impl TempData {
+    pub const LSB_PER_DEG: i16 = 8;
+
+    /// Temperature in °C, given the temperature at which the sensor reads 0
+    pub fn to_celsius(&self, offset: f32) -> f32 {
+        offset + self.0 as f32 / Self::LSB_PER_DEG as f32
+    }
+}
+
+impl<E, TI2C: WriteRead<Error = E> + Write<Error = E>> LSM303LDHC_MAG<TI2C> {
+    /// Expected contents of IRA_REG_M, IRB_REG_M and IRC_REG_M
+    pub const IDENTIFICATION: [u8; 3] = *b"H43";
+
+    pub fn init(i2c: &mut TI2C, data_rate: DataRate) -> Result<Self, Error<E>> {
+        let mut id = [0u8; 3];
+        i2c.write_read(LSM_MAG_I2C_ADDR, &[register::IRA_REG_M], &mut id)
+            .map_err(Error::NotFound)?;
+        if id != Self::IDENTIFICATION {
+            return Err(Error::WrongDevice);
+        }
+
+        i2c.write(
+            LSM_MAG_I2C_ADDR,
+            &[register::MR_REG_M, Mode::Continuous as u8],
+        )?;
         i2c.write(
             LSM_MAG_I2C_ADDR,
             &[register::CRA_REG_M, data_rate as u8 | 0x80],
         )?;
-        Ok(Self {
+
+        let mut mag = Self {
+            gain: Gain::Gauss1_3,
+            calibration: None,
             phantom: PhantomData,
-        })
+        };
+        mag.set_gain(i2c, Gain::Gauss1_3)?;
+        Ok(mag)
+    }
+
+    /// Select the operating mode. Use `read_single` to take single samples
+    /// while the sensor sleeps in between.
+    pub fn set_mode(&mut self, i2c: &mut TI2C, mode: Mode) -> Result<(), E> {
+        i2c.write(LSM_MAG_I2C_ADDR, &[register::MR_REG_M, mode as u8])
+    }
+
+    pub fn read_status(&mut self, i2c: &mut TI2C) -> Result<Status, E> {
+        self.read_reg(i2c, register::SR_REG_M).map(Status::from)
+    }
+
+    /// Trigger a single conversion. Poll for the result with `poll_sample`.
+    pub fn start_single(&mut self, i2c: &mut TI2C) -> Result<(), E> {
+        // Stop any continuous conversions, then read out a pending sample
+        // to clear DRDY, so that `poll_sample` only returns the new sample
+        self.set_mode(i2c, Mode::Sleep)?;
+        if self.read_status(i2c)?.data_ready {
+            self.read_sample(i2c)?;
+        }
+        self.set_mode(i2c, Mode::Single)
+    }
+
+    /// Read a sample once the data is ready and not locked,
+    /// or `WouldBlock` while the conversion is in progress
+    pub fn poll_sample(&mut self, i2c: &mut TI2C) -> nb::Result<MagData, E> {
+        let status = self.read_status(i2c)?;
+        if !status.data_ready || status.lock {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        Ok(self.read_sample(i2c)?)
+    }
+
+    /// Trigger a single conversion and block until it is done, polling every ms.
+    /// Returns `Error::Timeout` if the sample is not ready after `timeout_ms`,
+    /// so that a missing or stuck sensor does not hang the caller.
+    pub fn read_single(
+        &mut self,
+        i2c: &mut TI2C,
+        delay: &mut impl DelayMs<u8>,
+        timeout_ms: u8,
+    ) -> Result<MagData, Error<E>> {
+        self.start_single(i2c)?;
+        for _ in 0..=timeout_ms {
+            match self.poll_sample(i2c) {
+                Ok(sample) => return Ok(sample),
+                Err(nb::Error::WouldBlock) => delay.delay_ms(1),
+                Err(nb::Error::Other(e)) => return Err(Error::I2c(e)),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    pub fn set_gain(&mut self, i2c: &mut TI2C, gain: Gain) -> Result<(), E> {
+        i2c.write(LSM_MAG_I2C_ADDR, &[register::CRB_REG_M, gain as u8])?;
+        self.gain = gain;
+        Ok(())
+    }
+
+    /// Set the calibration that `read_field` applies to every reading
+    pub fn set_calibration(&mut self, calibration: Option<MagCalibration>) {
+        self.calibration = calibration;
+    }
+
+    pub fn calibration(&self) -> Option<&MagCalibration> {
+        self.calibration.as_ref()
     }
 
-    pub fn read_reg(&mut self, i2c: &mut TI2C, reg: u8) -> Result<u8, <TI2C as WriteRead>::Error> {
+    pub fn take_calibration(&mut self) -> Option<MagCalibration> {
+        self.calibration.take()
+    }
+
+    pub fn read_reg(&mut self, i2c: &mut TI2C, reg: u8) -> Result<u8, E> {
         let mut buf = [0u8];
         i2c.write_read(LSM_MAG_I2C_ADDR, &[reg], &mut buf)?;
 
         Ok(buf[0])
     }
 
-    pub fn read_sample(&mut self, i2c: &mut TI2C) -> Result<MagData, <TI2C as WriteRead>::Error> {
+    pub fn read_sample(&mut self, i2c: &mut TI2C) -> Result<MagData, E> {
         let mut buf = [0u8; 6];
         i2c.write_read(LSM_MAG_I2C_ADDR, &[register::OUT_X_H_M], &mut buf)?;
 
@@ -63,8 +248,36 @@ impl<TI2C: WriteRead + Write> LSM303LDHC_MAG<TI2C> {
         Ok(MagData { x, y, z })
     }
 
-    pub fn read_temp(&mut self, _i2c: &mut TI2C) -> Result<TempData, <TI2C as WriteRead>::Error> {
-        todo!()
+    /// Read a sample and convert it to milligauss using the selected gain,
+    /// applying the calibration if one is set.
+    /// Returns `Error::Saturated` if any axis is out of range.
+    pub fn read_field(&mut self, i2c: &mut TI2C) -> Result<MagField, Error<E>> {
+        let MagData { x, y, z } = self.read_sample(i2c)?;
+        if x == OVERFLOW || y == OVERFLOW || z == OVERFLOW {
+            return Err(Error::Saturated);
+        }
+
+        let (xy_lsb, z_lsb) = self.gain.lsb_per_gauss();
+        let field = MagField {
+            x: x as f32 * 1000. / xy_lsb as f32,
+            y: y as f32 * 1000. / xy_lsb as f32,
+            z: z as f32 * 1000. / z_lsb as f32,
+        };
+
+        Ok(match &self.calibration {
+            Some(calibration) => calibration.apply(&field),
+            None => field,
+        })
+    }
+
+    /// Read the temperature sensor, which is enabled by `init`
+    pub fn read_temp(&mut self, i2c: &mut TI2C) -> Result<TempData, E> {
+        let mut buf = [0u8; 2];
+        i2c.write_read(LSM_MAG_I2C_ADDR, &[register::TEMP_OUT_H_M], &mut buf)?;
+
+        // The 12 bits are left-aligned across the two registers,
+        // shift arithmetically to sign-extend
+        Ok(TempData(i16::from_be_bytes(buf) >> 4))
     }
 }
 
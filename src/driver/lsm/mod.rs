@@ -1,2 +1,22 @@
 pub mod acc;
+pub mod calibration;
 pub mod mag;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    /// A measurement exceeded the selected range
+    Saturated,
+    /// The device did not respond to the identification check
+    NotFound(E),
+    /// A device responded, but it is not the expected sensor
+    WrongDevice,
+    /// The sensor did not finish a conversion in time
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
@@ -1,4 +1,6 @@
+#[cfg(target_arch = "arm")]
 pub mod compass;
+pub mod l3gd20;
 pub mod lsm;
 pub mod motor;
 pub mod pca;
\ No newline at end of file
@@ -2,6 +2,10 @@ use crate::driver::pca::PCA9685;
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 
 use motor_direction::*;
+
+/// Largest speed value accepted by the PWM driver
+pub const MAX_SPEED: u16 = 0xFFF;
+
 pub struct Motors<TI2C> {
     pca: PCA9685<TI2C>,
 }
@@ -28,6 +32,37 @@ impl<TI2C: Write + WriteRead> Motors<TI2C> {
             .set_pwm(i2c, &(*motor_dir).into(), speed, 0xFFF - speed)
     }
 
+    /// Set the speed of the left and right side motors.
+    /// Positive speeds drive forward, negative speeds backward.
+    /// Opposite signs make the car spin in place.
+    pub fn set_speeds(
+        &mut self,
+        i2c: &mut TI2C,
+        left: i16,
+        right: i16,
+    ) -> Result<(), <TI2C as Write>::Error> {
+        self.set_side_speed(i2c, Motor::FrontLeft, Motor::RearLeft, left)?;
+        self.set_side_speed(i2c, Motor::FrontRight, Motor::RearRight, right)
+    }
+
+    fn set_side_speed(
+        &mut self,
+        i2c: &mut TI2C,
+        front: Motor,
+        rear: Motor,
+        speed: i16,
+    ) -> Result<(), <TI2C as Write>::Error> {
+        let dir = if speed < 0 {
+            Direction::Backward
+        } else {
+            Direction::Forward
+        };
+        let speed = (speed as i32).abs().min(MAX_SPEED as i32) as u16;
+
+        self.set_motor_speed(i2c, &(front, dir).into(), speed)?;
+        self.set_motor_speed(i2c, &(rear, dir).into(), speed)
+    }
+
     pub fn all_off(&mut self, i2c: &mut TI2C) -> Result<(), <TI2C as Write>::Error> {
         self.pca
             .set_pwm(i2c, &MotorDirection::flf().into(), 0x000, 0xFFF)?;
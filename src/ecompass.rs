@@ -0,0 +1,96 @@
+use libm::{atan2f, cosf, sinf};
+
+use crate::driver::lsm::acc::Acceleration;
+use crate::driver::lsm::mag::MagField;
+
+pub const RAD_TO_DEG: f32 = 180. / core::f32::consts::PI;
+pub const DEG_TO_RAD: f32 = core::f32::consts::PI / 180.;
+
+/// Orientation of the car in degrees.
+/// Roll is positive with the right side down, pitch is positive with the
+/// nose up, and heading runs clockwise from north in `[0, 360)`.
+#[derive(Clone, Debug)]
+pub struct Attitude {
+    pub roll: f32,
+    pub pitch: f32,
+    pub heading: f32,
+}
+
+/// Tilt-compensated electronic compass.
+///
+/// The LSM303DLHC axes point forward (x), left (y) and up (z). The readings
+/// are converted to the north-east-down frame used in Freescale AN4248,
+/// whose equations are used here.
+pub struct ECompass {
+    declination: f32,
+}
+
+impl ECompass {
+    /// `declination` is the angle in degrees between magnetic and true north,
+    /// positive when magnetic north lies east of true north
+    pub fn new(declination: f32) -> Self {
+        Self { declination }
+    }
+
+    pub fn set_declination(&mut self, declination: f32) {
+        self.declination = declination;
+    }
+
+    pub fn declination(&self) -> f32 {
+        self.declination
+    }
+
+    /// `acc` is in mg, calibrated if possible, as returned by `read_acceleration`
+    pub fn attitude(&self, acc: &Acceleration, mag: &MagField) -> Attitude {
+        // The accelerometer measures the reaction to gravity, so the
+        // gravity vector in NED is the negated, frame-converted reading
+        let gx = -acc.x;
+        let gy = acc.y;
+        let gz = acc.z;
+        let bx = mag.x;
+        let by = -mag.y;
+        let bz = -mag.z;
+
+        let roll = atan2f(gy, gz);
+        let (sin_roll, cos_roll) = (sinf(roll), cosf(roll));
+        let pitch = atan2f(-gx, gy * sin_roll + gz * cos_roll);
+        let (sin_pitch, cos_pitch) = (sinf(pitch), cosf(pitch));
+
+        // Rotate the magnetic field back to the horizontal plane
+        let hx = bx * cos_pitch + by * sin_pitch * sin_roll + bz * sin_pitch * cos_roll;
+        let hy = bz * sin_roll - by * cos_roll;
+        let heading = atan2f(hy, hx) * RAD_TO_DEG;
+
+        Attitude {
+            roll: roll * RAD_TO_DEG,
+            pitch: pitch * RAD_TO_DEG,
+            heading: wrap_degrees(heading + self.declination),
+        }
+    }
+
+    pub fn heading(&self, acc: &Acceleration, mag: &MagField) -> f32 {
+        self.attitude(acc, mag).heading
+    }
+}
+
+/// Wrap an angle in degrees to `[0, 360)`
+pub fn wrap_degrees(angle: f32) -> f32 {
+    let wrapped = angle % 360.;
+    let wrapped = if wrapped < 0. {
+        wrapped + 360.
+    } else {
+        wrapped
+    };
+    // Adding 360 to a tiny negative angle rounds to 360
+    if wrapped >= 360. {
+        0.
+    } else {
+        wrapped
+    }
+}
+
+/// The signed shortest rotation in degrees from `from` to `to`, in `[-180, 180)`.
+/// Positive values are clockwise.
+pub fn angle_difference(from: f32, to: f32) -> f32 {
+    wrap_degrees(to - from + 180.) - 180.
+}
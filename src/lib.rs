@@ -0,0 +1,9 @@
+//! Drivers and algorithms shared by the firmware in `main.rs`.
+//! Everything except the STM32F3-specific parts also builds for the host,
+//! so that it can be tested with `cargo test --lib --target <host triple>`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod ahrs;
+pub mod driver;
+pub mod ecompass;
+pub mod time;
@@ -10,14 +10,19 @@ use cortex_m_rt::entry;
 use stm32f3xx_hal::prelude::*;
 //use stm32f3xx_hal::stm32;
 
-mod driver;
-mod time;
+mod calibrate;
+mod nav;
+mod safety;
 mod usart;
 
+use four_wd_demo::{driver, ecompass, time};
+
+use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::i2c::{Write as HalWrite, WriteRead as HalWriteRead};
 
 use driver::compass::Compass;
 use driver::motor::Motors;
+use time::{BlockingDelay, Time};
 
 // A panic handler is run when the application encounters an error
 // it cannot recover from. The handler defines what it should do
@@ -31,29 +36,13 @@ unsafe fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-#[cfg(debug_assertions)]
-fn busy_wait(ms: u32) {
-    use cortex_m::asm::nop;
-
-    for _ in 0..(ms * 70) {
-        nop();
-    }
-}
-#[cfg(not(debug_assertions))]
-fn busy_wait(ms: u32) {
-    use cortex_m::asm::nop;
-
-    for _ in 0..(ms * 1400) {
-        nop();
-    }
-}
-
 // This is the main function, or entrypoint of our applicaton.
 #[entry]
 fn main() -> ! {
     use driver::compass::Led::*;
     // Get a handle to the peripherals.
     let peripherals = stm32f3xx_hal::stm32::Peripherals::take().unwrap();
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
 
     // Reset and clock control register
     let mut rcc = peripherals.RCC.constrain();
@@ -65,6 +54,11 @@ fn main() -> ! {
     let mut gpiob = peripherals.GPIOB.split(&mut rcc.ahb);
     let gpioe = peripherals.GPIOE.split(&mut rcc.ahb);
 
+    // Start the millisecond tick and the cycle counter used for delays
+    let time = Time::init(peripherals.TIM7, clocks, &mut rcc.apb1);
+    time.enable_cycle_counter(&mut core_peripherals.DCB, &mut core_peripherals.DWT, clocks);
+    let mut delay = BlockingDelay::new(time);
+
     // Init the compass using the GPIOE block
     let mut compass = Compass::init(gpioe);
 
@@ -87,7 +81,7 @@ fn main() -> ! {
     // let mut usart = usart::UsartWrite::init(usart1_tx);
 
     // // Blink North LED on compass to show that we've come this far
-    // compass.blink(North, 2).unwrap();
+    // compass.blink(North, 2, &mut delay).unwrap();
 
     // // Configure I2C pins
     // let scl = gpiob.pb6.into_af4(&mut gpiob.moder, &mut gpiob.afrl);
@@ -122,8 +116,8 @@ fn main() -> ! {
     loop {
         // TODO: Do something cool with the provided hardware
         compass.set_all_high().unwrap();
-        busy_wait(500);
+        delay.delay_ms(500u16);
         compass.set_all_low().unwrap();
-        busy_wait(500);
+        delay.delay_ms(500u16);
     }
 }
@@ -0,0 +1,101 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use super::pid::{Gains, Pid};
+use crate::driver::motor::{Motors, MAX_SPEED};
+use crate::ecompass::angle_difference;
+
+#[derive(Clone, Debug)]
+pub struct HeadingHoldConfig {
+    pub gains: Gains,
+    /// Limit of the integrated heading error, in degree-seconds
+    pub integral_limit: f32,
+    /// Largest speed difference applied to either side
+    pub max_correction: i16,
+}
+
+impl Default for HeadingHoldConfig {
+    fn default() -> Self {
+        Self {
+            gains: Gains {
+                kp: 40.,
+                ki: 5.,
+                kd: 2.,
+            },
+            integral_limit: 100.,
+            max_correction: 0x400,
+        }
+    }
+}
+
+/// Keeps the car on a heading by trimming the left and right side speeds
+/// while driving forward
+pub struct HeadingHold {
+    pid: Pid,
+    target: f32,
+}
+
+impl HeadingHold {
+    pub fn new(config: HeadingHoldConfig, target: f32) -> Self {
+        Self {
+            pid: Pid::new(
+                config.gains,
+                config.integral_limit,
+                config.max_correction as f32,
+            ),
+            target,
+        }
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Set the heading to hold, resetting the controller state
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+        self.pid.reset();
+    }
+
+    /// Change the heading to hold without resetting the controller state,
+    /// for following a target that moves gradually
+    pub fn steer_to(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    pub fn set_config(&mut self, config: HeadingHoldConfig) {
+        self.pid.set_gains(config.gains);
+        self.pid
+            .set_limits(config.integral_limit, config.max_correction as f32);
+    }
+
+    pub fn reset(&mut self) {
+        self.pid.reset();
+    }
+
+    /// Compute the left and right side speeds to hold the target heading,
+    /// given the current heading and the time in ms since the previous update
+    pub fn update(&mut self, heading: f32, speed: i16, dt_ms: u32) -> (i16, i16) {
+        // A positive error means the target lies clockwise,
+        // so the left side has to go faster
+        let error = angle_difference(heading, self.target);
+        let correction = self.pid.update(error, dt_ms as f32 / 1000.) as i32;
+
+        let max = MAX_SPEED as i32;
+        let left = (speed as i32 + correction).max(-max).min(max);
+        let right = (speed as i32 - correction).max(-max).min(max);
+        (left as i16, right as i16)
+    }
+
+    /// Update the controller and apply the resulting speeds to the motors
+    pub fn drive<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        heading: f32,
+        speed: i16,
+        dt_ms: u32,
+    ) -> Result<(), <TI2C as Write>::Error> {
+        let (left, right) = self.update(heading, speed, dt_ms);
+        motors.set_speeds(i2c, left, right)
+    }
+}
@@ -0,0 +1,174 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use super::heading_hold::{HeadingHold, HeadingHoldConfig};
+use super::odometry::Odometry;
+use super::turn::{Turn, TurnConfig, TurnResult};
+use crate::driver::compass::Compass;
+use crate::driver::motor::Motors;
+use crate::ecompass::angle_difference;
+use crate::safety::collision::{BumpDirection, BumpEvent};
+use crate::time::{Delay, Duration, Instant, Time};
+
+#[derive(Clone, Debug)]
+pub struct HomeConfig {
+    /// Distance in mm from the start within which the car stops
+    pub radius: f32,
+    /// Forward speed while driving home
+    pub speed: i16,
+    /// If the course is off by more than this many degrees,
+    /// the car stops and turns in place first
+    pub realign_angle: f32,
+    pub timeout: Duration,
+    pub turn: TurnConfig,
+    pub heading_hold: HeadingHoldConfig,
+}
+
+impl Default for HomeConfig {
+    fn default() -> Self {
+        Self {
+            radius: 100.,
+            speed: 0x800,
+            realign_angle: 45.,
+            timeout: Duration::from_secs(60),
+            turn: TurnConfig::default(),
+            heading_hold: HeadingHoldConfig::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AbortReason {
+    Bump(BumpDirection),
+    Rollover,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HomeResult {
+    Arrived,
+    Aborted(AbortReason),
+    TimedOut,
+    /// A turn towards the start timed out, for instance because the car is stuck
+    TurnTimedOut,
+}
+
+enum State {
+    Turning(Turn),
+    Driving,
+}
+
+/// Drives back to the origin of the odometry. Call `poll` from the main
+/// loop with the current heading until it returns a result.
+pub struct ReturnHome {
+    config: HomeConfig,
+    state: State,
+    hold: HeadingHold,
+    timeout: Delay,
+    last_poll: Instant,
+}
+
+impl ReturnHome {
+    pub fn new(config: HomeConfig, odometry: &Odometry, time: Time) -> Self {
+        let pose = odometry.pose();
+        let bearing = pose.bearing_to(0., 0.);
+        let timeout = Delay::new(config.timeout, time);
+
+        Self {
+            state: State::Turning(Turn::to_heading(bearing, config.turn.clone(), time)),
+            hold: HeadingHold::new(config.heading_hold.clone(), bearing),
+            timeout,
+            last_poll: time.current_time(),
+            config,
+        }
+    }
+
+    /// Stop the motors, turn off the compass LEDs and report a result
+    fn finish<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        compass: &mut Compass,
+        odometry: &mut Odometry,
+        result: HomeResult,
+    ) -> nb::Result<HomeResult, <TI2C as Write>::Error> {
+        compass.set_all_low().ok();
+        motors.all_off(i2c)?;
+        odometry.set_speeds(0, 0);
+        Ok(result)
+    }
+
+    /// Update the odometry and steer towards the start. Aborts if `bump`
+    /// holds a bump event or `rollover` is set, and points the compass
+    /// LEDs home while underway.
+    #[allow(clippy::too_many_arguments)]
+    pub fn poll<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        compass: &mut Compass,
+        odometry: &mut Odometry,
+        heading: f32,
+        bump: Option<&BumpEvent>,
+        rollover: bool,
+        time: Time,
+    ) -> nb::Result<HomeResult, <TI2C as Write>::Error> {
+        odometry.update(heading, time);
+
+        if let Some(bump) = bump {
+            let result = HomeResult::Aborted(AbortReason::Bump(bump.direction));
+            return self.finish(motors, i2c, compass, odometry, result);
+        }
+        if rollover {
+            let result = HomeResult::Aborted(AbortReason::Rollover);
+            return self.finish(motors, i2c, compass, odometry, result);
+        }
+
+        let pose = odometry.pose();
+        if pose.distance_to(0., 0.) <= self.config.radius {
+            return self.finish(motors, i2c, compass, odometry, HomeResult::Arrived);
+        }
+        if self.timeout.poll(time).is_ok() {
+            return self.finish(motors, i2c, compass, odometry, HomeResult::TimedOut);
+        }
+
+        let bearing = pose.bearing_to(0., 0.);
+        compass
+            .point_to(angle_difference(heading, bearing), false)
+            .ok();
+
+        let now = time.current_time();
+        let dt_ms = now.duration_since(self.last_poll).as_millis();
+        self.last_poll = now;
+
+        match &mut self.state {
+            State::Turning(turn) => {
+                odometry.set_speeds(0, 0);
+                match turn.poll(motors, i2c, heading, time) {
+                    Ok(TurnResult::Reached) => {
+                        self.hold.set_target(bearing);
+                        self.state = State::Driving;
+                    }
+                    Ok(TurnResult::TimedOut) => {
+                        let result = HomeResult::TurnTimedOut;
+                        return self.finish(motors, i2c, compass, odometry, result);
+                    }
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            State::Driving => {
+                if angle_difference(heading, bearing).abs() > self.config.realign_angle {
+                    let turn = Turn::to_heading(bearing, self.config.turn.clone(), time);
+                    self.state = State::Turning(turn);
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                // Follow the bearing as the position estimate changes
+                self.hold.steer_to(bearing);
+                let (left, right) = self.hold.update(heading, self.config.speed, dt_ms);
+                motors.set_speeds(i2c, left, right)?;
+                odometry.set_speeds(left, right);
+            }
+        }
+        Err(nb::Error::WouldBlock)
+    }
+}
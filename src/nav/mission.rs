@@ -0,0 +1,314 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use heapless::consts::U16;
+use heapless::Vec;
+use libm::{cosf, sinf};
+
+use super::heading_hold::{HeadingHold, HeadingHoldConfig};
+use super::odometry::Odometry;
+use super::turn::{Turn, TurnConfig, TurnResult};
+use crate::driver::motor::Motors;
+use crate::ecompass::DEG_TO_RAD;
+use crate::time::{Instant, Time};
+
+/// One leg of a mission
+#[derive(Copy, Clone, Debug)]
+pub enum Leg {
+    /// Drive `distance` mm on `heading` degrees
+    Relative { distance: f32, heading: f32 },
+    /// Drive to a position in mm in the odometry frame
+    Target { x: f32, y: f32 },
+}
+
+/// How the distance driven on a leg is determined
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DistanceMode {
+    /// Drive until the odometry reaches the end of the leg
+    Odometry,
+    /// Drive for the time the speed model predicts the leg takes
+    Timed,
+}
+
+#[derive(Clone, Debug)]
+pub struct MissionConfig {
+    pub speed: i16,
+    pub distance_mode: DistanceMode,
+    /// Distance in mm from the end of a leg at which it is considered done
+    pub tolerance: f32,
+    pub turn: TurnConfig,
+    pub heading_hold: HeadingHoldConfig,
+}
+
+impl Default for MissionConfig {
+    fn default() -> Self {
+        Self {
+            speed: 0x800,
+            distance_mode: DistanceMode::Odometry,
+            tolerance: 50.,
+            turn: TurnConfig::default(),
+            heading_hold: HeadingHoldConfig::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MissionEvent {
+    LegStarted(usize),
+    LegCompleted(usize),
+    /// The turn onto a leg timed out. The mission is paused,
+    /// `resume` retries the turn.
+    TurnTimedOut(usize),
+    Finished,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MissionState {
+    Running,
+    Paused,
+    Finished,
+    Aborted,
+}
+
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub leg: usize,
+    pub legs: usize,
+    /// Estimated distance left on the current leg in mm
+    pub remaining: f32,
+    pub state: MissionState,
+}
+
+enum State {
+    /// Set up the leg at `index`
+    Starting,
+    /// Turn to the leg heading before driving, after starting or resuming
+    Aligning,
+    Turning(Turn),
+    Driving,
+    Paused,
+    Finished,
+    Aborted,
+}
+
+/// Executes a list of legs in order. Call `poll` from the main loop
+/// with the current heading until it reports `MissionEvent::Finished`.
+pub struct Mission {
+    config: MissionConfig,
+    legs: Vec<Leg, U16>,
+    index: usize,
+    state: State,
+    /// Start and end of the current leg in the odometry frame
+    start: (f32, f32),
+    target: (f32, f32),
+    /// Driving time of the current leg in `DistanceMode::Timed`
+    duration_ms: u32,
+    driven_ms: u32,
+    hold: HeadingHold,
+    last_poll: Instant,
+}
+
+impl Mission {
+    pub fn new(config: MissionConfig) -> Self {
+        Self {
+            hold: HeadingHold::new(config.heading_hold.clone(), 0.),
+            config,
+            legs: Vec::new(),
+            index: 0,
+            state: State::Starting,
+            start: (0., 0.),
+            target: (0., 0.),
+            duration_ms: 0,
+            driven_ms: 0,
+            last_poll: Instant::default(),
+        }
+    }
+
+    /// Append a leg, returning it back if the mission is full
+    pub fn push(&mut self, leg: Leg) -> Result<(), Leg> {
+        self.legs.push(leg)
+    }
+
+    /// Remove all legs and start over
+    pub fn clear(&mut self) {
+        self.legs.clear();
+        self.index = 0;
+        self.state = State::Starting;
+    }
+
+    pub fn state(&self) -> MissionState {
+        match self.state {
+            State::Paused => MissionState::Paused,
+            State::Finished => MissionState::Finished,
+            State::Aborted => MissionState::Aborted,
+            _ => MissionState::Running,
+        }
+    }
+
+    pub fn progress(&self, odometry: &Odometry) -> Progress {
+        let remaining = match self.config.distance_mode {
+            DistanceMode::Odometry => odometry.pose().distance_to(self.target.0, self.target.1),
+            DistanceMode::Timed => {
+                let ms = self.duration_ms.saturating_sub(self.driven_ms);
+                odometry.model().ground_speed(self.config.speed) * ms as f32 / 1000.
+            }
+        };
+
+        Progress {
+            leg: self.index,
+            legs: self.legs.len(),
+            remaining,
+            state: self.state(),
+        }
+    }
+
+    /// Stop the motors and hold the mission until `resume` is called
+    pub fn pause<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        odometry: &mut Odometry,
+    ) -> Result<(), <TI2C as Write>::Error> {
+        if matches!(
+            self.state,
+            State::Turning(_) | State::Driving | State::Aligning
+        ) {
+            self.state = State::Paused;
+        }
+        odometry.set_speeds(0, 0);
+        motors.all_off(i2c)
+    }
+
+    /// Continue a paused mission, turning back onto the leg's heading first
+    pub fn resume(&mut self) {
+        if let State::Paused = self.state {
+            self.state = State::Aligning;
+        }
+    }
+
+    /// Stop the motors and end the mission
+    pub fn abort<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        odometry: &mut Odometry,
+    ) -> Result<(), <TI2C as Write>::Error> {
+        self.state = State::Aborted;
+        odometry.set_speeds(0, 0);
+        motors.all_off(i2c)
+    }
+
+    /// Heading from the current position towards the end of the leg
+    fn leg_heading(&self, odometry: &Odometry) -> f32 {
+        odometry.pose().bearing_to(self.target.0, self.target.1)
+    }
+
+    /// Compute the end of the current leg from the current pose
+    fn start_leg(&mut self, odometry: &Odometry) {
+        let pose = odometry.pose();
+        self.start = (pose.x, pose.y);
+        self.target = match self.legs[self.index] {
+            Leg::Relative { distance, heading } => {
+                let rad = heading * DEG_TO_RAD;
+                (pose.x + distance * cosf(rad), pose.y + distance * sinf(rad))
+            }
+            Leg::Target { x, y } => (x, y),
+        };
+
+        let distance = pose.distance_to(self.target.0, self.target.1);
+        let speed = odometry.model().ground_speed(self.config.speed);
+        self.duration_ms = if speed > 0. {
+            (distance / speed * 1000.) as u32
+        } else {
+            0
+        };
+        self.driven_ms = 0;
+    }
+
+    /// Whether the end of the current leg has been reached or passed
+    fn leg_done(&self, odometry: &Odometry) -> bool {
+        match self.config.distance_mode {
+            DistanceMode::Timed => self.driven_ms >= self.duration_ms,
+            DistanceMode::Odometry => {
+                let pose = odometry.pose();
+                let distance = pose.distance_to(self.target.0, self.target.1);
+                // Once the car has passed the end of the leg, the remaining
+                // vector points against the direction of the leg
+                let leg = (self.target.0 - self.start.0, self.target.1 - self.start.1);
+                let remaining = (self.target.0 - pose.x, self.target.1 - pose.y);
+                let passed = leg.0 * remaining.0 + leg.1 * remaining.1 < 0.;
+                distance <= self.config.tolerance || passed
+            }
+        }
+    }
+
+    /// Update the odometry and drive the current leg.
+    /// Returns an event when a leg starts or completes, or the mission finishes.
+    pub fn poll<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        odometry: &mut Odometry,
+        heading: f32,
+        time: Time,
+    ) -> Result<Option<MissionEvent>, <TI2C as Write>::Error> {
+        odometry.update(heading, time);
+        let now = time.current_time();
+        let dt_ms = now.duration_since(self.last_poll).as_millis();
+        self.last_poll = now;
+
+        match &mut self.state {
+            State::Starting => {
+                if self.index >= self.legs.len() {
+                    self.state = State::Finished;
+                    return Ok(Some(MissionEvent::Finished));
+                }
+                self.start_leg(odometry);
+                self.state = State::Aligning;
+                Ok(Some(MissionEvent::LegStarted(self.index)))
+            }
+            State::Aligning => {
+                let turn =
+                    Turn::to_heading(self.leg_heading(odometry), self.config.turn.clone(), time);
+                self.state = State::Turning(turn);
+                Ok(None)
+            }
+            State::Turning(turn) => {
+                odometry.set_speeds(0, 0);
+                match turn.poll(motors, i2c, heading, time) {
+                    Ok(TurnResult::Reached) => {
+                        self.hold.set_target(self.leg_heading(odometry));
+                        self.state = State::Driving;
+                        Ok(None)
+                    }
+                    Ok(TurnResult::TimedOut) => {
+                        self.state = State::Paused;
+                        Ok(Some(MissionEvent::TurnTimedOut(self.index)))
+                    }
+                    Err(nb::Error::WouldBlock) => Ok(None),
+                    Err(nb::Error::Other(e)) => Err(e),
+                }
+            }
+            State::Driving => {
+                self.driven_ms = self.driven_ms.saturating_add(dt_ms);
+                if self.leg_done(odometry) {
+                    motors.all_off(i2c)?;
+                    odometry.set_speeds(0, 0);
+                    let completed = self.index;
+                    self.index += 1;
+                    self.state = State::Starting;
+                    return Ok(Some(MissionEvent::LegCompleted(completed)));
+                }
+
+                // In timed mode the odometry may not be accurate enough
+                // to steer by, so keep the heading the leg started with
+                if self.config.distance_mode == DistanceMode::Odometry {
+                    self.hold.steer_to(self.leg_heading(odometry));
+                }
+                let (left, right) = self.hold.update(heading, self.config.speed, dt_ms);
+                motors.set_speeds(i2c, left, right)?;
+                odometry.set_speeds(left, right);
+                Ok(None)
+            }
+            State::Paused | State::Finished | State::Aborted => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod heading_hold;
+pub mod home;
+pub mod mission;
+pub mod odometry;
+pub mod pid;
+pub mod turn;
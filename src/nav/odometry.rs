@@ -0,0 +1,141 @@
+use libm::{atan2f, cosf, sinf, sqrtf};
+
+use crate::ecompass::{angle_difference, wrap_degrees, DEG_TO_RAD};
+use crate::time::{Instant, Time};
+
+/// Maps commanded motor speeds to ground speed
+#[derive(Clone, Debug)]
+pub struct SpeedModel {
+    /// Ground speed in mm/s per unit of motor speed above the deadband
+    pub mm_per_s_per_unit: f32,
+    /// Motor speeds below which the car does not move
+    pub deadband: i16,
+    /// Growth of the position variance in mm² per mm travelled
+    pub distance_variance: f32,
+    /// Growth of the position variance in mm² per second, moving or not
+    pub time_variance: f32,
+}
+
+impl Default for SpeedModel {
+    fn default() -> Self {
+        Self {
+            mm_per_s_per_unit: 0.15,
+            deadband: 0x200,
+            distance_variance: 5.,
+            time_variance: 10.,
+        }
+    }
+}
+
+impl SpeedModel {
+    /// Ground speed in mm/s of a side driven at `speed`
+    pub fn ground_speed(&self, speed: i16) -> f32 {
+        let magnitude = (speed as i32).abs() - self.deadband as i32;
+        if magnitude <= 0 {
+            return 0.;
+        }
+        let ground_speed = magnitude as f32 * self.mm_per_s_per_unit;
+        if speed < 0 {
+            -ground_speed
+        } else {
+            ground_speed
+        }
+    }
+}
+
+/// Position in mm relative to the start, with x pointing north and y east,
+/// and the heading in degrees
+#[derive(Clone, Debug, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+}
+
+impl Pose {
+    pub fn distance_to(&self, x: f32, y: f32) -> f32 {
+        let (dx, dy) = (x - self.x, y - self.y);
+        sqrtf(dx * dx + dy * dy)
+    }
+
+    /// Heading in degrees from this pose towards a position
+    pub fn bearing_to(&self, x: f32, y: f32) -> f32 {
+        wrap_degrees(atan2f(y - self.y, x - self.x) / DEG_TO_RAD)
+    }
+}
+
+/// Dead-reckoning pose estimate from the commanded wheel speeds and
+/// the measured heading
+pub struct Odometry {
+    model: SpeedModel,
+    pose: Pose,
+    /// Variance of the position in mm²
+    variance: f32,
+    speeds: (i16, i16),
+    last_update: Option<Instant>,
+}
+
+impl Odometry {
+    pub fn new(model: SpeedModel) -> Self {
+        Self {
+            model,
+            pose: Pose::default(),
+            variance: 0.,
+            speeds: (0, 0),
+            last_update: None,
+        }
+    }
+
+    /// Make the current position the origin, with full confidence
+    pub fn reset(&mut self) {
+        self.pose = Pose {
+            heading: self.pose.heading,
+            ..Pose::default()
+        };
+        self.variance = 0.;
+    }
+
+    pub fn model(&self) -> &SpeedModel {
+        &self.model
+    }
+
+    pub fn pose(&self) -> &Pose {
+        &self.pose
+    }
+
+    /// Standard deviation of the position estimate in mm
+    pub fn uncertainty(&self) -> f32 {
+        sqrtf(self.variance)
+    }
+
+    /// Record the speeds the motors were set to, which apply from the
+    /// next `update` on. Call `update` first to account for the old speeds.
+    pub fn set_speeds(&mut self, left: i16, right: i16) {
+        self.speeds = (left, right);
+    }
+
+    /// Integrate the motion since the previous update, given the current heading
+    pub fn update(&mut self, heading: f32, time: Time) {
+        let now = time.current_time();
+        let prev_heading = self.pose.heading;
+        self.pose.heading = heading;
+
+        let dt = match self.last_update.replace(now) {
+            Some(last) => now.duration_since(last).as_secs_f32(),
+            None => return,
+        };
+
+        let (left, right) = self.speeds;
+        let speed = (self.model.ground_speed(left) + self.model.ground_speed(right)) / 2.;
+        let distance = speed * dt;
+
+        // Integrate along the average heading over the interval
+        let mid_heading = prev_heading + angle_difference(prev_heading, heading) / 2.;
+        let rad = mid_heading * DEG_TO_RAD;
+        self.pose.x += distance * cosf(rad);
+        self.pose.y += distance * sinf(rad);
+
+        self.variance +=
+            self.model.distance_variance * distance.abs() + self.model.time_variance * dt;
+    }
+}
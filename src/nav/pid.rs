@@ -0,0 +1,73 @@
+#[derive(Clone, Debug)]
+pub struct Gains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+/// A PID controller with integral windup protection and a clamped output
+#[derive(Clone, Debug)]
+pub struct Pid {
+    gains: Gains,
+    /// Limit of the absolute value of the integrated error
+    integral_limit: f32,
+    /// Limit of the absolute value of the output
+    output_limit: f32,
+    integral: f32,
+    prev_error: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(gains: Gains, integral_limit: f32, output_limit: f32) -> Self {
+        Self {
+            gains,
+            integral_limit,
+            output_limit,
+            integral: 0.,
+            prev_error: None,
+        }
+    }
+
+    pub fn gains(&self) -> &Gains {
+        &self.gains
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.gains = gains;
+    }
+
+    pub fn set_limits(&mut self, integral_limit: f32, output_limit: f32) {
+        self.integral_limit = integral_limit;
+        self.output_limit = output_limit;
+        self.integral = clamp(self.integral, integral_limit);
+    }
+
+    /// Forget the accumulated integral and previous error
+    pub fn reset(&mut self) {
+        self.integral = 0.;
+        self.prev_error = None;
+    }
+
+    /// Compute the controller output for `error`, `dt` seconds after the previous update
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        self.integral = clamp(self.integral + error * dt, self.integral_limit);
+
+        // Skip the derivative on the first update, rather than
+        // kicking on the step from zero
+        let derivative = match self.prev_error {
+            Some(prev) if dt > 0. => (error - prev) / dt,
+            _ => 0.,
+        };
+        self.prev_error = Some(error);
+
+        let Gains { kp, ki, kd } = self.gains;
+        clamp(
+            kp * error + ki * self.integral + kd * derivative,
+            self.output_limit,
+        )
+    }
+}
+
+fn clamp(value: f32, limit: f32) -> f32 {
+    value.max(-limit).min(limit)
+}
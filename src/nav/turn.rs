@@ -0,0 +1,166 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::driver::motor::Motors;
+use crate::ecompass::{angle_difference, wrap_degrees};
+use crate::time::{Delay, Duration, Instant, Time};
+
+/// Shortest interval over which the turn rate is measured. The heading is
+/// noisy and the clock has 1 ms resolution, so back-to-back polls would
+/// give a rate that jumps around.
+const RATE_INTERVAL: Duration = Duration::from_millis(50);
+/// Weight of a new rate measurement in the filtered turn rate
+const RATE_SMOOTHING: f32 = 0.5;
+
+#[derive(Clone, Debug)]
+pub struct TurnConfig {
+    /// The turn is done once the heading is within this many degrees of the target
+    pub tolerance: f32,
+    /// Spin speed per degree of remaining error
+    pub kp: f32,
+    /// Lowest spin speed, needed to overcome friction
+    pub min_speed: i16,
+    pub max_speed: i16,
+    /// Maximum turn rate in degrees per second
+    pub max_rate: f32,
+    pub timeout: Duration,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 5.,
+            kp: 20.,
+            min_speed: 0x300,
+            max_speed: 0x800,
+            max_rate: 90.,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TurnResult {
+    Reached,
+    TimedOut,
+}
+
+/// A non-blocking spin in place towards a heading. Call `poll` from the
+/// main loop with fresh heading readings until it returns a result.
+pub struct Turn {
+    config: TurnConfig,
+    target: f32,
+    /// For relative turns, the rotation still to go and the heading it was
+    /// measured at, so that turns of more than half a turn go the right way
+    remaining: Option<(f32, f32)>,
+    timeout: Delay,
+    prev: Option<(f32, Instant)>,
+    /// Filtered turn rate in degrees per second
+    rate: f32,
+    speed_scale: f32,
+}
+
+impl Turn {
+    /// Turn to face `target` degrees
+    pub fn to_heading(target: f32, config: TurnConfig, time: Time) -> Self {
+        let timeout = Delay::new(config.timeout, time);
+        Self {
+            config,
+            target: wrap_degrees(target),
+            remaining: None,
+            timeout,
+            prev: None,
+            rate: 0.,
+            speed_scale: 1.,
+        }
+    }
+
+    /// Rotate by `degrees` relative to `heading`, clockwise if positive.
+    /// The rotation is accumulated from the headings passed to `poll`,
+    /// which must be polled often enough to see less than half a turn
+    /// between polls.
+    pub fn relative(heading: f32, degrees: f32, config: TurnConfig, time: Time) -> Self {
+        Self {
+            remaining: Some((degrees, heading)),
+            ..Self::to_heading(heading + degrees, config, time)
+        }
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Update the spin speed for the current heading.
+    /// Stops the motors and returns the result once the target is reached
+    /// or the timeout expires.
+    pub fn poll<TI2C: Write + WriteRead>(
+        &mut self,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        heading: f32,
+        time: Time,
+    ) -> nb::Result<TurnResult, <TI2C as Write>::Error> {
+        let error = match &mut self.remaining {
+            Some((remaining, last_heading)) => {
+                *remaining -= angle_difference(*last_heading, heading);
+                *last_heading = heading;
+                *remaining
+            }
+            None => angle_difference(heading, self.target),
+        };
+        if error.abs() <= self.config.tolerance {
+            motors.all_off(i2c)?;
+            return Ok(TurnResult::Reached);
+        }
+        if self.timeout.poll(time).is_ok() {
+            motors.all_off(i2c)?;
+            return Ok(TurnResult::TimedOut);
+        }
+
+        self.limit_rate(heading, time.current_time());
+
+        let TurnConfig {
+            kp,
+            min_speed,
+            max_speed,
+            ..
+        } = self.config;
+        // Scale after clamping, so that the rate limit also holds
+        // where the proportional speed is below the minimum
+        let speed = ((error.abs() * kp)
+            .max(min_speed as f32)
+            .min(max_speed as f32)
+            * self.speed_scale) as i16;
+
+        // Positive errors are clockwise: left side forward, right side backward
+        if error > 0. {
+            motors.set_speeds(i2c, speed, -speed)?;
+        } else {
+            motors.set_speeds(i2c, -speed, speed)?;
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Scale down the spin speed while the measured turn rate exceeds the maximum
+    fn limit_rate(&mut self, heading: f32, now: Instant) {
+        let (prev_heading, prev_time) = match self.prev {
+            Some(prev) => prev,
+            None => {
+                self.prev = Some((heading, now));
+                return;
+            }
+        };
+        let elapsed = now.duration_since(prev_time);
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+
+        let rate = angle_difference(prev_heading, heading).abs() / elapsed.as_secs_f32();
+        self.rate += (rate - self.rate) * RATE_SMOOTHING;
+        self.speed_scale = if self.rate > self.config.max_rate {
+            self.speed_scale * self.config.max_rate / self.rate
+        } else {
+            (self.speed_scale * 1.1).min(1.)
+        };
+        self.prev = Some((heading, now));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::driver::lsm::acc::Acceleration;
+use crate::time::Duration;
+
+/// The side of the car that hit something
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BumpDirection {
+    Front,
+    Back,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Debug)]
+pub struct BumpEvent {
+    pub direction: BumpDirection,
+    /// Magnitude of the horizontal acceleration in mg
+    pub magnitude: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct CollisionConfig {
+    /// Horizontal acceleration in mg above which a bump is detected
+    pub threshold: f32,
+    /// Change in horizontal acceleration in mg/s above which a bump is detected
+    pub jerk_threshold: f32,
+    /// Time after a bump during which no further bumps are reported
+    pub holdoff: Duration,
+    /// Cutoff of the software high-pass filter in Hz. Set to `None` if the
+    /// accelerometer's own high-pass filter is applied to its output.
+    pub high_pass_cutoff: Option<f32>,
+}
+
+impl Default for CollisionConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 800.,
+            jerk_threshold: 40_000.,
+            holdoff: Duration::from_millis(500),
+            high_pass_cutoff: Some(2.),
+        }
+    }
+}
+
+/// Detects bumps from sudden horizontal accelerations. Gravity and slow
+/// changes from accelerating and braking are removed with a high-pass filter.
+pub struct CollisionDetector {
+    config: CollisionConfig,
+    /// Time between samples in seconds
+    dt: f32,
+    /// High-pass filter coefficient
+    alpha: f32,
+    prev_input: Option<(f32, f32)>,
+    filtered: (f32, f32),
+    holdoff: u32,
+}
+
+impl CollisionDetector {
+    /// Create a detector that is fed `rate_hz` samples per second
+    pub fn new(config: CollisionConfig, rate_hz: u32) -> Self {
+        let dt = 1. / rate_hz as f32;
+        let alpha = match config.high_pass_cutoff {
+            Some(cutoff) => {
+                let rc = 1. / (2. * core::f32::consts::PI * cutoff);
+                rc / (rc + dt)
+            }
+            None => 1.,
+        };
+
+        Self {
+            config,
+            dt,
+            alpha,
+            prev_input: None,
+            filtered: (0., 0.),
+            holdoff: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.prev_input = None;
+        self.filtered = (0., 0.);
+        self.holdoff = 0;
+    }
+
+    /// Feed a sample, returning an event if a bump was detected
+    pub fn update(&mut self, acc: &Acceleration) -> Option<BumpEvent> {
+        let input = (acc.x, acc.y);
+        let prev_filtered = self.filtered;
+        self.filtered = match (self.config.high_pass_cutoff, self.prev_input) {
+            (None, _) => input,
+            (Some(_), Some(prev)) => (
+                self.alpha * (prev_filtered.0 + input.0 - prev.0),
+                self.alpha * (prev_filtered.1 + input.1 - prev.1),
+            ),
+            // Wait for a second sample before the filter produces output
+            (Some(_), None) => (0., 0.),
+        };
+        let first = self.prev_input.is_none();
+        self.prev_input = Some(input);
+
+        if self.holdoff > 0 {
+            self.holdoff -= 1;
+            return None;
+        }
+        if first {
+            return None;
+        }
+
+        let (x, y) = self.filtered;
+        let magnitude = libm::sqrtf(x * x + y * y);
+        let (dx, dy) = (x - prev_filtered.0, y - prev_filtered.1);
+        let jerk = libm::sqrtf(dx * dx + dy * dy) / self.dt;
+        if magnitude < self.config.threshold && jerk < self.config.jerk_threshold {
+            return None;
+        }
+
+        self.holdoff = (self.config.holdoff.as_secs_f32() / self.dt) as u32;
+
+        // The impact pushes the car away from the obstacle
+        let direction = if x.abs() >= y.abs() {
+            if x < 0. {
+                BumpDirection::Front
+            } else {
+                BumpDirection::Back
+            }
+        } else if y < 0. {
+            BumpDirection::Left
+        } else {
+            BumpDirection::Right
+        };
+
+        Some(BumpEvent {
+            direction,
+            magnitude,
+        })
+    }
+}
@@ -0,0 +1,156 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use libm::{atan2f, sqrtf};
+
+use crate::driver::compass::{Compass, LedError};
+use crate::driver::lsm::acc::Acceleration;
+use crate::driver::motor::Motors;
+use crate::ecompass::RAD_TO_DEG;
+use crate::time::{Duration, Instant, Time};
+
+#[derive(Clone, Debug)]
+pub struct RolloverConfig {
+    /// Largest absolute roll in degrees considered safe
+    pub max_roll: f32,
+    /// Largest absolute pitch in degrees considered safe
+    pub max_pitch: f32,
+    /// Time the car must be tilted past the limits before the fault trips
+    pub trip_time: Duration,
+    /// Time the car must be upright again before the fault clears
+    pub clear_time: Duration,
+}
+
+impl Default for RolloverConfig {
+    fn default() -> Self {
+        Self {
+            max_roll: 35.,
+            max_pitch: 35.,
+            trip_time: Duration::from_millis(200),
+            clear_time: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Tilt of the car in degrees, computed from the accelerometer alone
+#[derive(Clone, Debug)]
+pub struct Tilt {
+    /// Positive with the right side down
+    pub roll: f32,
+    /// Positive with the nose up
+    pub pitch: f32,
+    /// Direction of the lowest side, clockwise from the front,
+    /// or `None` if the car is level or upside down
+    pub low_side: Option<f32>,
+}
+
+impl Tilt {
+    pub fn from_sample(acc: &Acceleration) -> Self {
+        let (x, y, z) = (acc.x, acc.y, acc.z);
+        let horizontal = sqrtf(x * x + y * y);
+        let low_side = if horizontal > sqrtf(x * x + y * y + z * z) / 4. {
+            // The accelerometer measures the reaction to gravity, which
+            // points away from the low side. The y axis points left.
+            Some(atan2f(y, -x) * RAD_TO_DEG)
+        } else {
+            None
+        };
+
+        Self {
+            roll: atan2f(y, z) * RAD_TO_DEG,
+            pitch: atan2f(x, sqrtf(y * y + z * z)) * RAD_TO_DEG,
+            low_side,
+        }
+    }
+}
+
+/// Cuts the motors when the car flips over or is lifted at an angle,
+/// and keeps them off until the car is upright again
+pub struct RolloverMonitor {
+    config: RolloverConfig,
+    /// Start of the current period in which the car is tilted or upright
+    since: Option<Instant>,
+    fault: bool,
+    tilt: Option<Tilt>,
+    /// Whether `show` has lit the LEDs for the current fault
+    shown: bool,
+}
+
+impl RolloverMonitor {
+    pub fn new(config: RolloverConfig) -> Self {
+        Self {
+            config,
+            since: None,
+            fault: false,
+            tilt: None,
+            shown: false,
+        }
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fault
+    }
+
+    pub fn tilt(&self) -> Option<&Tilt> {
+        self.tilt.as_ref()
+    }
+
+    /// Feed a sample taken at `now` ms, returning whether the fault is latched
+    pub fn update(&mut self, acc: &Acceleration, now: Instant) -> bool {
+        let tilt = Tilt::from_sample(acc);
+        let tilted =
+            tilt.roll.abs() > self.config.max_roll || tilt.pitch.abs() > self.config.max_pitch;
+        self.tilt = Some(tilt);
+
+        // While the fault is latched we wait for the car to be upright,
+        // otherwise for it to be tilted
+        let waiting_for = if self.fault { !tilted } else { tilted };
+        if !waiting_for {
+            self.since = None;
+            return self.fault;
+        }
+
+        let since = *self.since.get_or_insert(now);
+        let period = if self.fault {
+            self.config.clear_time
+        } else {
+            self.config.trip_time
+        };
+        if now.duration_since(since) >= period {
+            self.fault = !self.fault;
+            self.since = None;
+        }
+        self.fault
+    }
+
+    /// Feed a sample and cut the motors for as long as the fault is latched
+    pub fn check<TI2C: Write + WriteRead>(
+        &mut self,
+        acc: &Acceleration,
+        motors: &mut Motors<TI2C>,
+        i2c: &mut TI2C,
+        time: Time,
+    ) -> Result<bool, <TI2C as Write>::Error> {
+        if self.update(acc, time.current_time()) {
+            motors.all_off(i2c)?;
+        }
+        Ok(self.fault)
+    }
+
+    /// Show the fault on the compass: the LED on the low side lights up,
+    /// or all LEDs if the car is upside down. The LEDs are turned off once
+    /// when the fault clears, and left alone otherwise.
+    pub fn show(&mut self, compass: &mut Compass) -> Result<(), LedError> {
+        if !self.fault {
+            if self.shown {
+                compass.set_all_low()?;
+                self.shown = false;
+            }
+            return Ok(());
+        }
+        match self.tilt.as_ref().and_then(|tilt| tilt.low_side) {
+            Some(bearing) => compass.point_to(bearing, false)?,
+            None => compass.set_all_high()?,
+        }
+        self.shown = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use core::cmp::Ordering;
+use core::ops::{Add, AddAssign, Mul, Sub};
+
+#[cfg(target_arch = "arm")]
+mod timer;
+
+#[cfg(target_arch = "arm")]
+pub use timer::{BlockingDelay, Stopwatch, Time, Timestamp};
+
+/// A source of the current time, implemented by `Time`
+pub trait Clock {
+    fn current_time(&self) -> Instant;
+}
+
+/// A point in time in ms since boot. The counter wraps around after about
+/// 49 days, so instants are compared by their wrapping difference. This is
+/// correct as long as the instants compared are less than about 24 days apart.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Instant(u32);
+
+impl Instant {
+    pub fn from_millis(ms: u32) -> Self {
+        Self(ms)
+    }
+
+    pub fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier` is later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        if self >= earlier {
+            Duration(self.0.wrapping_sub(earlier.0))
+        } else {
+            Duration(0)
+        }
+    }
+}
+
+impl PartialOrd for Instant {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some((self.0.wrapping_sub(other.0) as i32).cmp(&0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        Instant(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        Instant(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// A span of time in ms
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration(u32);
+
+impl Duration {
+    pub const fn from_millis(ms: u32) -> Self {
+        Self(ms)
+    }
+
+    pub const fn from_secs(secs: u32) -> Self {
+        Self(secs.saturating_mul(1000))
+    }
+
+    pub fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    pub fn as_secs_f32(self) -> f32 {
+        self.0 as f32 / 1000.
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+    fn add(self, rhs: Duration) -> Self::Output {
+        Duration(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        Duration(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Mul<u32> for Duration {
+    type Output = Duration;
+    fn mul(self, rhs: u32) -> Self::Output {
+        Duration(self.0.saturating_mul(rhs))
+    }
+}
+
+pub struct Delay {
+    target_time: Instant,
+}
+
+impl Delay {
+    pub fn new(delay: Duration, clock: impl Clock) -> Self {
+        Self {
+            target_time: clock.current_time() + delay,
+        }
+    }
+
+    pub fn poll(&self, clock: impl Clock) -> nb::Result<(), core::convert::Infallible> {
+        if self.target_time <= clock.current_time() {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+/// What an `Interval` does with ticks that were missed because it was
+/// polled too late
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MissedTicks {
+    /// Fire the missed ticks on subsequent polls, so that the total number
+    /// of ticks matches the elapsed time
+    CatchUp,
+    /// Drop the missed ticks and continue at the next period boundary
+    Skip,
+}
+
+/// Fires once every period. Each tick is scheduled relative to the previous
+/// target rather than to the time of polling, so the ticks don't drift.
+pub struct Interval {
+    period: Duration,
+    next_tick: Instant,
+    missed_ticks: MissedTicks,
+    /// Ticks still to catch up on that were already reported as missed
+    reported: u32,
+}
+
+impl Interval {
+    pub fn new(period: Duration, clock: impl Clock) -> Self {
+        Self::with_missed_ticks(period, MissedTicks::Skip, clock)
+    }
+
+    pub fn with_missed_ticks(
+        period: Duration,
+        missed_ticks: MissedTicks,
+        clock: impl Clock,
+    ) -> Self {
+        let period = period.max(Duration::from_millis(1));
+        Self {
+            period,
+            next_tick: clock.current_time() + period,
+            missed_ticks,
+            reported: 0,
+        }
+    }
+
+    /// Returns `WouldBlock` until the next tick is due. On a tick, returns the
+    /// number of ticks missed because the loop overran: with `MissedTicks::Skip`
+    /// these were dropped, with `MissedTicks::CatchUp` they are still due and
+    /// fire on the next polls. Each missed tick is reported once.
+    pub fn poll(&mut self, clock: impl Clock) -> nb::Result<u32, core::convert::Infallible> {
+        let now = clock.current_time();
+        if now < self.next_tick {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let behind = now.duration_since(self.next_tick).as_millis() / self.period.as_millis();
+        match self.missed_ticks {
+            MissedTicks::CatchUp => {
+                // This tick is one of those reported as missed before
+                let missed = behind.saturating_sub(self.reported.saturating_sub(1));
+                self.reported = behind;
+                self.next_tick += self.period;
+                Ok(missed)
+            }
+            MissedTicks::Skip => {
+                self.next_tick += self.period * (behind + 1);
+                Ok(behind)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// A clock that only moves when told to
+    struct TestClock(Cell<Instant>);
+
+    impl TestClock {
+        fn at(ms: u32) -> Self {
+            Self(Cell::new(Instant::from_millis(ms)))
+        }
+
+        fn advance(&self, ms: u32) {
+            self.0.set(self.0.get() + Duration::from_millis(ms));
+        }
+    }
+
+    impl Clock for &TestClock {
+        fn current_time(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    const BEFORE_WRAP: u32 = u32::MAX - 5;
+
+    #[test]
+    fn instant_orders_across_wrap() {
+        let start = Instant::from_millis(BEFORE_WRAP);
+        let later = start + Duration::from_millis(10);
+
+        assert_eq!(later.as_millis(), 4);
+        assert!(later > start);
+        assert!(start < later);
+        assert_eq!(later - Duration::from_millis(10), start);
+    }
+
+    #[test]
+    fn duration_since_across_wrap() {
+        let start = Instant::from_millis(BEFORE_WRAP);
+        let later = start + Duration::from_millis(10);
+
+        assert_eq!(later.duration_since(start), Duration::from_millis(10));
+        assert_eq!(later - start, Duration::from_millis(10));
+        assert_eq!(start.duration_since(later), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn duration_from_secs_saturates() {
+        assert_eq!(Duration::from_secs(5), Duration::from_millis(5000));
+        assert_eq!(
+            Duration::from_secs(u32::MAX),
+            Duration::from_millis(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn delay_across_wrap() {
+        let clock = TestClock::at(BEFORE_WRAP);
+        let delay = Delay::new(Duration::from_millis(10), &clock);
+
+        clock.advance(5);
+        assert!(delay.poll(&clock).is_err());
+        // The counter has wrapped, but the target is still 1 ms ahead
+        clock.advance(4);
+        assert!(delay.poll(&clock).is_err());
+        clock.advance(1);
+        assert!(delay.poll(&clock).is_ok());
+        clock.advance(1000);
+        assert!(delay.poll(&clock).is_ok());
+    }
+
+    #[test]
+    fn interval_skip_across_wrap() {
+        let clock = TestClock::at(BEFORE_WRAP - 10);
+        let mut interval =
+            Interval::with_missed_ticks(Duration::from_millis(10), MissedTicks::Skip, &clock);
+
+        // The next tick is due at BEFORE_WRAP
+        clock.advance(9);
+        assert!(interval.poll(&clock).is_err());
+        clock.advance(1);
+        assert_eq!(interval.poll(&clock), Ok(0));
+
+        // The next tick is due 4 ms after the wrap. Overrun by
+        // three periods, the missed ticks are dropped.
+        clock.advance(10 + 30);
+        assert_eq!(interval.poll(&clock), Ok(3));
+        assert!(interval.poll(&clock).is_err());
+        clock.advance(10);
+        assert_eq!(interval.poll(&clock), Ok(0));
+    }
+
+    #[test]
+    fn interval_catch_up_across_wrap() {
+        let clock = TestClock::at(BEFORE_WRAP);
+        let mut interval =
+            Interval::with_missed_ticks(Duration::from_millis(10), MissedTicks::CatchUp, &clock);
+
+        // The first tick is due after the wrap. Overrun by three periods,
+        // the missed ticks are reported once and then fire one per poll.
+        clock.advance(10 + 30);
+        assert_eq!(interval.poll(&clock), Ok(3));
+        assert_eq!(interval.poll(&clock), Ok(0));
+        assert_eq!(interval.poll(&clock), Ok(0));
+        assert_eq!(interval.poll(&clock), Ok(0));
+        assert!(interval.poll(&clock).is_err());
+
+        // Falling behind again while catching up only reports the new misses
+        clock.advance(25);
+        assert_eq!(interval.poll(&clock), Ok(1));
+        assert_eq!(interval.poll(&clock), Ok(0));
+        assert!(interval.poll(&clock).is_err());
+        clock.advance(5);
+        assert_eq!(interval.poll(&clock), Ok(0));
+    }
+}
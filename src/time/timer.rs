@@ -0,0 +1,222 @@
+use core::cell::RefCell;
+use core::ops::DerefMut;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt::free as interrupt_free;
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use cortex_m::peripheral::{DCB, DWT};
+
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use stm32f3xx_hal::prelude::*;
+use stm32f3xx_hal::rcc::{Clocks, APB1};
+use stm32f3xx_hal::stm32;
+use stm32f3xx_hal::timer::{Event, Timer};
+
+use stm32::{interrupt, Interrupt};
+
+use super::{Clock, Delay, Duration, Instant};
+
+static CURRENT_TIME: AtomicU32 = AtomicU32::new(0);
+
+static TIMER_TIM7: Mutex<RefCell<Option<Timer<stm32::TIM7>>>> = Mutex::new(RefCell::new(None));
+
+/// Upper half of the 64-bit cycle count
+static CYCLES_HIGH: AtomicU32 = AtomicU32::new(0);
+/// CYCCNT as of the previous read, to detect overflows
+static CYCLES_LAST: AtomicU32 = AtomicU32::new(0);
+/// Core clock in MHz, zero while the cycle counter is disabled
+static CYCLES_PER_US: AtomicU32 = AtomicU32::new(0);
+
+#[interrupt]
+fn TIM7() {
+    interrupt_free(|cs| {
+        if let Some(ref mut tim7) = TIMER_TIM7.borrow(cs).borrow_mut().deref_mut() {
+            tim7.clear_update_interrupt_flag();
+        }
+        CURRENT_TIME.fetch_add(1, Ordering::Relaxed);
+        // CYCCNT overflows every 60 s at 72 MHz,
+        // so reading it every ms keeps track of all overflows
+        if cycle_counter_enabled() {
+            cycle_count(cs);
+        }
+    });
+}
+
+#[derive(Copy, Clone)]
+pub struct Time {}
+
+impl Time {
+    pub fn init(tim7: stm32::TIM7, clocks: Clocks, apb1: &mut APB1) -> Self {
+        let mut tim7 = Timer::tim7(tim7, 1.khz(), clocks, apb1);
+        tim7.listen(Event::Update);
+
+        interrupt_free(|cs| {
+            TIMER_TIM7.borrow(cs).replace(Some(tim7));
+        });
+
+        unsafe {
+            stm32::NVIC::unmask(Interrupt::TIM7);
+        }
+
+        Self {}
+    }
+
+    pub fn current_time(self) -> Instant {
+        Instant::from_millis(CURRENT_TIME.load(Ordering::Relaxed))
+    }
+
+    /// Start the DWT cycle counter, which backs `timestamp`.
+    /// The TIM7 interrupt extends the counter to 64 bits, so `init` must have been called.
+    pub fn enable_cycle_counter(self, dcb: &mut DCB, dwt: &mut DWT, clocks: Clocks) {
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+        CYCLES_PER_US.store((clocks.sysclk().0 / 1_000_000).max(1), Ordering::Relaxed);
+    }
+
+    /// A microsecond resolution timestamp. Always zero until
+    /// `enable_cycle_counter` is called, as CYCCNT is not running before
+    /// then and its value is unknown.
+    pub fn timestamp(self) -> Timestamp {
+        if !cycle_counter_enabled() {
+            return Timestamp::default();
+        }
+        Timestamp(interrupt_free(cycle_count))
+    }
+
+    /// Run `f`, returning its result and how long it took in µs
+    pub fn measure<R>(self, f: impl FnOnce() -> R) -> (R, u64) {
+        let stopwatch = Stopwatch::start(self);
+        let res = f();
+        (res, stopwatch.elapsed_us(self))
+    }
+}
+
+impl Clock for Time {
+    fn current_time(&self) -> Instant {
+        Time::current_time(*self)
+    }
+}
+
+fn cycle_counter_enabled() -> bool {
+    CYCLES_PER_US.load(Ordering::Relaxed) != 0
+}
+
+/// Read CYCCNT, extended to 64 bits. Must be called at least once per overflow period.
+fn cycle_count(_cs: &CriticalSection) -> u64 {
+    let low = DWT::get_cycle_count();
+    let mut high = CYCLES_HIGH.load(Ordering::Relaxed);
+    if low < CYCLES_LAST.load(Ordering::Relaxed) {
+        high = high.wrapping_add(1);
+        CYCLES_HIGH.store(high, Ordering::Relaxed);
+    }
+    CYCLES_LAST.store(low, Ordering::Relaxed);
+
+    (high as u64) << 32 | low as u64
+}
+
+/// A point in time in core clock cycles. CYCCNT is not reset when it is
+/// enabled, so only differences between timestamps are meaningful.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn as_cycles(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_micros(self) -> u64 {
+        self.0 / CYCLES_PER_US.load(Ordering::Relaxed).max(1) as u64
+    }
+
+    /// Microseconds elapsed from `earlier` to `self`, or zero if `earlier` is later
+    pub fn micros_since(self, earlier: Timestamp) -> u64 {
+        Timestamp(self.0.saturating_sub(earlier.0)).as_micros()
+    }
+}
+
+/// Measures the time taken by a section of code
+pub struct Stopwatch {
+    start: Timestamp,
+}
+
+impl Stopwatch {
+    pub fn start(time: Time) -> Self {
+        Self {
+            start: time.timestamp(),
+        }
+    }
+
+    /// Microseconds elapsed since the stopwatch was started
+    pub fn elapsed_us(&self, time: Time) -> u64 {
+        time.timestamp().micros_since(self.start)
+    }
+
+    /// Return the elapsed microseconds and restart the stopwatch
+    pub fn lap(&mut self, time: Time) -> u64 {
+        let now = time.timestamp();
+        let elapsed = now.micros_since(self.start);
+        self.start = now;
+        elapsed
+    }
+}
+
+/// Blocking delays based on the TIM7 tick, and on the cycle counter
+/// for microsecond delays when it is enabled. Unlike a calibrated nop loop,
+/// these don't depend on the optimisation level or the clock configuration.
+#[derive(Copy, Clone)]
+pub struct BlockingDelay {
+    time: Time,
+}
+
+impl BlockingDelay {
+    pub fn new(time: Time) -> Self {
+        Self { time }
+    }
+}
+
+impl DelayMs<u32> for BlockingDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        // The current tick has already partially passed,
+        // so wait for one more tick to delay at least `ms`
+        let delay = Delay::new(Duration::from_millis(ms.saturating_add(1)), self.time);
+        while delay.poll(self.time).is_err() {}
+    }
+}
+
+impl DelayMs<u16> for BlockingDelay {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl DelayMs<u8> for BlockingDelay {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl DelayUs<u32> for BlockingDelay {
+    fn delay_us(&mut self, us: u32) {
+        if !cycle_counter_enabled() {
+            // Without the cycle counter, round up to whole ms
+            let ms = us.saturating_add(999) / 1000;
+            return self.delay_ms(ms);
+        }
+
+        let start = self.time.timestamp().as_cycles();
+        let cycles = us as u64 * CYCLES_PER_US.load(Ordering::Relaxed) as u64;
+        while self.time.timestamp().as_cycles().saturating_sub(start) < cycles {}
+    }
+}
+
+impl DelayUs<u16> for BlockingDelay {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(us as u32);
+    }
+}
+
+impl DelayUs<u8> for BlockingDelay {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(us as u32);
+    }
+}